@@ -1,150 +1,924 @@
 use prelude::*;
-use {Config,Setup};
+use std::{
+  io,
+  time::{Instant,Duration},
+};
+use {Config,Setup,MouseMove};
+use network::{Remote};
 use rect::*;
-use network;
+use self::packet::{Packet,Headers,DecodeError};
+use self::latency::{PingTracker,LatencySummary};
+use self::touch::{Contacts,Contact,Phase,Sample};
+use self::jitter::{JitterBuffer};
+use self::clock::{ClockSync,ClockEstimate};
+use self::capability::{Capability,Capabilities};
+use self::sequence::{SequenceTracker,Arrival,LossSummary,SampleDedup};
+use self::compact::{CompactTouch,CompactScale,CompactDecoder};
 
-pub const ABSM_VERSION: (u16,u16)=(1,0);
+pub mod packet;
+pub mod latency;
+pub mod touch;
+pub mod clock;
+pub mod capability;
+pub mod version;
+pub mod sequence;
+pub mod jitter;
+pub mod coalesce;
+pub mod compact;
+
+pub const ABSM_VERSION: (u16,u16)=(1,11);
+
+///Coalesce at most this many waiting packets at once, so that a flood of packets can't hold
+///back the cursor forever.
+const MAX_COALESCE: usize=256;
+
+///Handshake headers set from typed configuration fields, which `extra_headers` may not reuse.
+const RESERVED_HEADERS: &[&str]=&[
+  "client_name","capabilities","password","frame_delay","update_delay","keepalive","redundancy",
+];
 
 thread_local!{
   static NET_BUFFER: NetBuffer=Default::default();
 }
 
+///The stages of an `absM` connection, see `absm-protocol.md`.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Stage {
+  ///There is no connection to the device.
+  Disconnected,
+  ///The handshake-open was sent, waiting for the server-info reply.
+  Connecting,
+  ///The handshake is done and events are flowing.
+  Connected,
+}
+impl fmt::Display for Stage {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    f.write_str(match self {
+      Stage::Disconnected=>"disconnected",
+      Stage::Connecting=>"connecting",
+      Stage::Connected=>"connected",
+    })
+  }
+}
+
 pub struct AbsmSession {
   config: Config,
-  server_info: ServerInfo,
-  setup: Setup,
-  connection: Box<Connection>,
+  stage: Stage,
+  ///When the current stage times out, if ever.
+  deadline: Option<Instant>,
+  connection: Option<Box<dyn Connection>>,
+  ///Server info from the last handshake, kept while disconnected.
+  server_info: Option<ServerInfo>,
+  ///Setup from the last handshake, kept while disconnected.
+  setup: Option<Setup>,
+  ///Pointers currently touching the device screen.
+  contacts: Contacts,
+  pings: PingTracker,
+  ///Minor protocol version shared by both sides.
+  minor: u16,
+  ///Optional features supported by both sides, agreed on in the handshake.
+  capabilities: Capabilities,
+  ///Sequence numbers of `'seqn'` packets, to spot lost and late datagrams.
+  sequence: SequenceTracker,
+  ///Sample sequence numbers of `'rdnt'` packets, to skip repeated samples.
+  samples: SampleDedup,
+  ///Last sample of `'cmov'` and `'dmov'` packets, to apply deltas to.
+  compact: CompactDecoder,
+  ///Buffer smoothing out the delivery of timestamped samples, if enabled.
+  jitter: Option<JitterBuffer>,
+  ///How many samples were merged into newer ones while coalescing.
+  merged: u64,
+  ///Device clock estimate, from timestamped ping replies.
+  clock: ClockSync,
+  ///Smoothed delay between the device sampling a touch and the cursor moving, in seconds.
+  input_delay: Option<f32>,
+  ///When to send the next ping, if pinging is enabled and connected.
+  next_ping: Option<Instant>,
+  ///When to log latency statistics next, if enabled and connected.
+  next_latency_log: Option<Instant>,
+  ///Keepalive interval agreed on in the handshake, if any.
+  keepalive: Option<Duration>,
+  ///How many previous samples the device agreed to repeat in `'rdnt'` packets, if any.
+  redundancy: Option<u8>,
+  ///When to send a keepalive, if nothing else is sent before.
+  next_keepalive: Option<Instant>,
 }
 impl AbsmSession {
-  ///Create an `AbsmSession` from the given configuration.
+  ///Create a disconnected `AbsmSession` from the given configuration.
   pub fn new(config: Config)->AbsmSession {
+    let jitter=if config.jitter_buffer {
+      Some(JitterBuffer::new(config.jitter_delay,config.jitter_max_delay))
+    }else{
+      None
+    };
+    AbsmSession{
+      jitter,
+      config,
+      stage: Stage::Disconnected,
+      deadline: None,
+      connection: None,
+      server_info: None,
+      setup: None,
+      contacts: Contacts::new(),
+      pings: PingTracker::new(128),
+      minor: 0,
+      capabilities: Capabilities::none(),
+      sequence: SequenceTracker::new(),
+      samples: SampleDedup::new(),
+      compact: CompactDecoder::new(),
+      merged: 0,
+      clock: ClockSync::new(32),
+      input_delay: None,
+      next_ping: None,
+      next_latency_log: None,
+      keepalive: None,
+      redundancy: None,
+      next_keepalive: None,
+    }
+  }
+  
+  ///Round-trip time statistics over the latest ping replies.
+  pub fn latency(&self)->Option<LatencySummary> {
+    self.pings.summary()
+  }
+  ///Packet loss statistics over the current connection, if it uses sequence numbers.
+  pub fn loss(&self)->Option<LossSummary> {
+    self.sequence.summary()
+  }
+  ///Device clock estimate over the latest timestamped ping replies, for converting device
+  ///timestamps to local instants.
+  pub fn clock_estimate(&self)->Option<ClockEstimate> {
+    self.clock.estimate()
+  }
+  
+  ///Open a new connection to the device and send the handshake-open, moving to the `Connecting`
+  ///stage.
+  ///Any previous connection is dropped, but the setup is kept until the device sends new server
+  ///info.
+  pub fn connect(&mut self)->Result<()> {
+    self.disconnect();
+    
     //Create connection
-    println!("connecting to device at {}...",config.remote);
-    let mut conn=config.remote.connect().expect("failed to connect to server");
+    println!("connecting to device at {}...",self.config.remote);
+    let mut conn=self.config.remote.connect(
+      self.config.max_packet_size as usize,
+      duration_from_secs(self.config.connect_timeout),
+    )?;
     
     //Send open message
     println!("sending handshake-open message");
-    NET_BUFFER.borrow(|buf| {
-      buf.extend_from_slice(b"absM");
-      network::encode_into(&mut *buf,&ABSM_VERSION).unwrap();
-      {
-        let mut header=|key,val| encode_header(&mut *buf,key,val);
-        header(b"client_name",b"desktop-rust");
-        header(b"frame_delay",&network::f32_to_bytes(0.25))
-      }
-      conn.send(&buf).expect("failed to send handshake-open");
-    });
+    let headers=self.handshake_headers();
+    send_packet(&mut *conn,&Packet::HandshakeOpen{version: ABSM_VERSION,headers})?;
     
-    //Receive server info
+    self.connection=Some(conn);
+    self.set_stage(Stage::Connecting);
+    Ok(())
+  }
+  
+  ///Build the handshake-open headers from the configuration.
+  fn handshake_headers(&self)->Headers {
+    let config=&self.config;
+    let mut headers=Headers::new();
+    headers.insert_raw("client_name",&*config.client_name);
+    headers.insert_raw("capabilities",local_capabilities(&self.config).encode());
+    if !config.password.is_empty() {
+      headers.insert_raw("password",&*config.password);
+    }
+    if let Some(frame_delay)=config.frame_delay {
+      headers.insert("frame_delay",&frame_delay);
+    }
+    if let Some(update_delay)=config.update_delay {
+      headers.insert("update_delay",&update_delay);
+    }
+    if let Some(keepalive)=config.keepalive_interval {
+      headers.insert("keepalive",&keepalive);
+    }
+    if let Some(redundancy)=config.redundancy {
+      headers.insert("redundancy",&redundancy);
+    }
+    for (key,val) in config.extra_headers.iter() {
+      if RESERVED_HEADERS.contains(&&**key) {
+        println!("skipping extra header '{}', set through its own config field instead",key);
+        continue;
+      }
+      headers.insert_raw(key,&**val);
+    }
+    headers
+  }
+  
+  ///Drop the connection, if any, moving to the `Disconnected` stage.
+  pub fn disconnect(&mut self) {
+    self.close(Some("client disconnected"));
+  }
+  
+  ///Drop the connection, telling the device why with a `'bye '` packet if a reason is given.
+  fn close(&mut self,reason: Option<&str>) {
+    if let (Some(reason),Some(conn))=(reason,self.connection.as_mut()) {
+      if self.stage!=Stage::Disconnected {
+        //The connection may already be broken, so failing to say goodbye is fine
+        let _=send_packet(&mut **conn,&Packet::Bye(reason.to_string()));
+      }
+    }
+    self.connection=None;
+    self.set_stage(Stage::Disconnected);
+  }
+  
+  ///Connect to the device and block until the handshake completes.
+  pub fn reconnect(&mut self)->Result<()> {
+    self.connect()?;
     println!("waiting for server-info reply");
-    let server_info=NET_BUFFER.borrow(|buf| {
-      conn.recv(&mut *buf).expect("failed to receive server-info");
-      ServerInfo::from_message(buf)
-    });
-    
-    //Create setup and notify to server
-    println!("building setup");
-    let setup=server_info.build(&config);
-    println!("sending setup to server");
-    NET_BUFFER.borrow(|buf| {
-      buf.extend_from_slice(b"setp");
-      {
-        let mut header=|key,val| encode_header(&mut *buf,key,val);
-        //header();
-      }
-      conn.send(buf).expect("failed to send setup information");
-    });
+    while self.stage==Stage::Connecting {
+      match self.wait_for_event() {
+        Err(ref err) if err.recovery()==Recovery::Skip=>println!("skipping packet: {}",err),
+        result=>result?,
+      }
+    }
+    Ok(())
+  }
+  
+  ///Listen on the connection until a packet is received or the current stage times out, and act
+  ///upon the packet.
+  ///A malformed packet is reported as an `Error::Malformed`, after which the session can
+  ///continue.
+  ///On any other error the session moves to the `Disconnected` stage.
+  pub fn wait_for_event(&mut self)->Result<()> {
+    let result=self.recv_and_consume();
+    if let Err(ref err)=result {
+      match err {
+        //No point in saying goodbye to a device that already left
+        Error::Closed(_)=>self.close(None),
+        err if err.recovery()!=Recovery::Skip=>self.close(Some(&err.to_string())),
+        _ => {},
+      }
+    }
+    result
+  }
+  
+  fn recv_and_consume(&mut self)->Result<()> {
+    let now=Instant::now();
+    if let Some(deadline)=self.deadline {
+      if now>=deadline {
+        return Err(Error::Timeout(self.stage));
+      }
+    }
+    self.run_timers(now)?;
     
-    //Create state
-    AbsmSession{config,server_info,setup,connection: conn}
-  }
-  
-  ///Listen on the connection for events, consuming and returning when one is received.
-  pub fn wait_for_event(&mut self) {
-    NET_BUFFER.borrow(|buf| {
-      //Read message
-      self.connection.recv(&mut *buf).expect("failed to receive message from server");
-      //Parse message
-      self.consume_message(buf);
-    });
-  }
-  
-  ///Parse a single message.
-  pub fn consume_message(&mut self,msg: &mut [u8]) {
-    assert!(msg.len()>4,"invalid abs-m message header");
-    let mut ty=[0; 4];
-    ty.copy_from_slice(&msg[0..4]);
-    match &ty {
-      b"tuch"=>{
-        
+    //Wait at most until the stage deadline or the next timer
+    let next_release=self.jitter.as_ref().and_then(|jitter| jitter.next_release());
+    let wake=[self.deadline,self.next_ping,self.next_latency_log,self.next_keepalive,next_release]
+      .iter().filter_map(|&t| t).min();
+    let timeout=wake.map(|wake| if wake>now {wake-now}else{Duration::from_secs(0)});
+    let packet={
+      let conn=match self.connection {
+        Some(ref mut conn)=>conn,
+        None=>return Err(Error::Io(io::ErrorKind::NotConnected.into())),
+      };
+      conn.set_timeout(timeout)?;
+      match recv_packet(&mut **conn) {
+        //Timers and deadlines are checked on the next call
+        Err(Error::Io(ref err)) if is_timeout(err)=>return Ok(()),
+        result=>result?,
+      }
+    };
+    //Any packet keeps an established connection alive
+    if self.stage==Stage::Connected {
+      self.set_stage(Stage::Connected);
+      if self.config.coalesce {
+        let mut packets=vec![packet];
+        self.drain_waiting(&mut packets)?;
+        return self.consume_coalesced(packets);
+      }
+    }
+    self.consume_packet(packet)
+  }
+  
+  ///Receive every packet that already arrived, without waiting, up to `MAX_COALESCE` packets.
+  fn drain_waiting(&mut self,packets: &mut Vec<Packet>)->Result<()> {
+    let conn=match self.connection {
+      Some(ref mut conn)=>conn,
+      None=>return Ok(()),
+    };
+    while packets.len()<MAX_COALESCE {
+      match try_recv_packet(&mut **conn) {
+        Ok(Some(packet))=>packets.push(packet),
+        Ok(None)=>break,
+        Err(ref err) if err.recovery()==Recovery::Skip=>println!("skipping packet: {}",err),
+        Err(err)=>return Err(err),
+      }
+    }
+    Ok(())
+  }
+  
+  ///Act upon several packets, applying only the newest position between transitions.
+  fn consume_coalesced(&mut self,packets: Vec<Packet>)->Result<()> {
+    let sequenced=self.capabilities.contains(Capability::Sequence);
+    let packets=if sequenced {coalesce::unwrap_sequenced(packets,&mut self.sequence)}else{packets};
+    //Duplicates are gone, so every compact sample left can be decoded in order
+    let packets=packets.into_iter().filter_map(|packet| self.expand_compact(packet)).collect();
+    let coalesced=coalesce::coalesce(packets);
+    self.merged+=coalesced.merged as u64;
+    for packet in coalesced.packets {
+      let result=match packet {
+        //Sequence numbers were already sorted out, anything still wrapped is late
+        Packet::Sequenced{packet,..} if sequenced=>self.consume_late(*packet),
+        packet=>self.consume_packet(packet),
+      };
+      match result {
+        Err(ref err) if err.recovery()==Recovery::Skip=>println!("skipping packet: {}",err),
+        result=>result?,
+      }
+    }
+    Ok(())
+  }
+  
+  ///Turn compact touches into plain ones before coalescing, since merging away a compact
+  ///sample would leave the deltas after it with nothing to apply to.
+  ///Returns `None` for deltas that can't be applied.
+  fn expand_compact(&mut self,packet: Packet)->Option<Packet> {
+    match packet {
+      Packet::Compact(touch) if self.capabilities.contains(Capability::Compact)=>{
+        self.decode_compact(touch).map(Packet::Touch)
       },
-      b"keyp"=>{
-        
+      packet=>Some(packet),
+    }
+  }
+  
+  ///Rebuild the touch carried by a compact packet, unless it is a delta against a sample that
+  ///was not received.
+  fn decode_compact(&mut self,touch: CompactTouch)->Option<MouseMove> {
+    let sample=self.compact.decode(touch)?;
+    self.server_info.as_ref().map(|info| CompactScale::new(info).decode(sample))
+  }
+  
+  ///Act upon a single packet, according to the current stage.
+  pub fn consume_packet(&mut self,packet: Packet)->Result<()> {
+    //The device may leave at any stage
+    let packet=match packet {
+      Packet::Bye(reason)=>return Err(Error::Closed(reason)),
+      packet=>packet,
+    };
+    //Buffered samples must not be overtaken by other events
+    match packet {
+      Packet::Touch(..) | Packet::Compact(..) | Packet::Key(..) | Packet::Pen(..) | Packet::MultiTouch(..)=>{
+        self.flush_jitter();
       },
-      b"sInf"=>{
-        self.server_info.update(msg);
+      _ => {},
+    }
+    match self.stage {
+      Stage::Disconnected=>{
+        println!("ignored '{}' message while disconnected",String::from_utf8_lossy(&packet.ty()));
       },
-      b"ping"=>{
-        msg[0..4].copy_from_slice(b"repl");
-        self.connection.send(msg).expect("failed to send ping reply");
+      Stage::Connecting=>match packet {
+        Packet::ServerInfo{version,headers}=>{
+          //Finish handshake
+          let server_info=ServerInfo::from_message(version,&headers)?;
+          self.replace_server_info(server_info);
+          println!("sending setup to server");
+          self.send(&Packet::Setup{headers: Headers::new()})?;
+          self.set_stage(Stage::Connected);
+        },
+        packet=>println!(
+          "ignored '{}' message, expecting a server-info",
+          String::from_utf8_lossy(&packet.ty())
+        ),
       },
-      ty=>{
-        println!("unknown message type '{}'",String::from_utf8_lossy(ty));
+      Stage::Connected=>match packet {
+        ref packet if !self.is_agreed(packet)=>println!(
+          "ignored '{}' message, its capability was not agreed on",
+          String::from_utf8_lossy(&packet.ty())
+        ),
+        Packet::Touch(ev)=>if let Some(ref mut setup)=self.setup {setup.consume(ev)},
+        Packet::Compact(touch)=>if let Some(ev)=self.decode_compact(touch) {
+          if let Some(ref mut setup)=self.setup {setup.consume(ev)}
+        },
+        Packet::Key(ev)=>if let Some(ref mut setup)=self.setup {setup.consume_key(ev)},
+        Packet::Pen(pen)=>if let Some(ref mut setup)=self.setup {setup.consume_pen(pen)},
+        Packet::Batch(samples)=>self.consume_samples(samples),
+        Packet::Redundant{seq,mut samples}=>{
+          //Only apply the samples newer than any seen before
+          let fresh=self.samples.fresh(seq,samples.len());
+          let repeated=samples.len()-fresh;
+          self.consume_samples(samples.split_off(repeated));
+        },
+        Packet::MultiTouch(contacts)=>self.consume_contacts(contacts,false),
+        Packet::ServerInfo{version,headers}=>{
+          let server_info=match self.server_info.clone() {
+            Some(mut server_info)=>{
+              server_info.update(version,&headers)?;
+              server_info
+            },
+            None=>ServerInfo::from_message(version,&headers)?,
+          };
+          self.replace_server_info(server_info);
+        },
+        Packet::Sequenced{seq,packet}=>match self.sequence.on_packet(seq) {
+          Arrival::Fresh=>self.consume_packet(*packet)?,
+          Arrival::Late=>self.consume_late(*packet)?,
+          Arrival::Duplicate=>{},
+        },
+        Packet::KeepAlive=>{},
+        Packet::Ping(data)=>self.send(&Packet::Reply(data))?,
+        Packet::Reply(data)=>{
+          let now=Instant::now();
+          match self.pings.on_reply(&data,now) {
+            Some(rtt)=>if self.capabilities.contains(Capability::ClockSync) {
+              if let Some(device_time)=clock::reply_timestamp(&data) {
+                self.clock.add_exchange(now-rtt,now,device_time);
+              }
+            },
+            None=>println!("ignored ping reply not matching any pending ping"),
+          }
+        },
+        packet=>{
+          println!("unhandled message type '{}'",String::from_utf8_lossy(&packet.ty()));
+        },
       },
     }
+    Ok(())
+  }
+  
+  ///Act upon a packet that arrived after a newer one.
+  ///Late positions would make the cursor jump back, but state changes still apply.
+  fn consume_late(&mut self,packet: Packet)->Result<()> {
+    match packet {
+      ref packet if packet.is_position()=>{},
+      Packet::MultiTouch(contacts)=>if self.capabilities.contains(Capability::MultiTouch) {
+        self.consume_contacts(contacts,true);
+      },
+      packet=>self.consume_packet(packet)?,
+    }
+    Ok(())
+  }
+  
+  ///Update pointer state with multi-touch contacts, moving the cursor with the primary pointer.
+  ///Late contacts only touch down and lift pointers, since newer positions already superseded
+  ///theirs.
+  fn consume_contacts(&mut self,contacts: Vec<Contact>,late: bool) {
+    for contact in contacts {
+      if late && contact.phase==Phase::Move {
+        continue;
+      }
+      //Only the primary pointer moves the cursor
+      if let Some(primary)=self.contacts.update(contact) {
+        if !late && (primary.phase==Phase::Down || primary.phase==Phase::Move) {
+          if let Some(ref mut setup)=self.setup {setup.consume(primary.ev)}
+        }
+      }
+    }
+  }
+  
+  ///Apply timestamped samples in order, through the jitter buffer if enabled.
+  fn consume_samples(&mut self,samples: Vec<Sample>) {
+    match self.jitter {
+      Some(ref mut jitter)=>{
+        let now=Instant::now();
+        for sample in samples {
+          jitter.push(sample,now);
+        }
+      },
+      None=>self.apply_samples(samples),
+    }
+  }
+  
+  ///Immediately apply every sample held in the jitter buffer.
+  fn flush_jitter(&mut self) {
+    let samples=match self.jitter {
+      Some(ref mut jitter)=>jitter.drain(),
+      None=>return,
+    };
+    self.apply_samples(samples);
+  }
+  
+  ///Move the cursor through samples right away, measuring the input-to-cursor delay of the
+  ///newest one.
+  fn apply_samples(&mut self,samples: Vec<Sample>) {
+    if let Some(ref mut setup)=self.setup {
+      let newest=samples.last().map(|sample| sample.time);
+      for sample in samples {
+        setup.consume(sample.ev);
+      }
+      //Measure the true input-to-cursor delay once the clocks are synchronized
+      if let Some(sampled_at)=newest.and_then(|time| self.clock.device_to_local(time)) {
+        let now=Instant::now();
+        if now>sampled_at {
+          let delay=secs_from_duration(now-sampled_at) as f32;
+          self.input_delay=Some(self.input_delay.map_or(delay,|avg| avg*0.9+delay*0.1));
+        }
+      }
+    }
+  }
+  
+  ///Send a packet through the current connection.
+  fn send(&mut self,packet: &Packet)->Result<()> {
+    match self.connection {
+      Some(ref mut conn)=>send_packet(&mut **conn,packet)?,
+      None=>return Err(Error::Io(io::ErrorKind::NotConnected.into())),
+    }
+    //Anything sent counts as a keepalive
+    if self.next_keepalive.is_some() {
+      self.next_keepalive=self.keepalive.map(|interval| Instant::now()+interval);
+    }
+    Ok(())
+  }
+  
+  ///Send pings and log statistics when due.
+  fn run_timers(&mut self,now: Instant)->Result<()> {
+    if self.stage!=Stage::Connected {
+      return Ok(());
+    }
+    //Release buffered samples at their original spacing
+    let due=match self.jitter {
+      Some(ref mut jitter)=>jitter.pop_due(now),
+      None=>Vec::new(),
+    };
+    if !due.is_empty() {
+      self.apply_samples(due);
+    }
+    //Check that the device still replies to pings
+    if let Some(sent)=self.pings.oldest_pending() {
+      let waited=now-sent;
+      if waited>duration_from_secs(self.config.ping_timeout) {
+        println!("no ping reply from device in {:?}",waited);
+        return Err(Error::Timeout(Stage::Connected));
+      }
+    }
+    if let Some(next_ping)=self.next_ping {
+      if now>=next_ping {
+        let data=self.pings.make_ping(now);
+        self.send(&Packet::Ping(data))?;
+        self.next_ping=self.config.ping_interval.map(|secs| now+duration_from_secs(secs));
+      }
+    }
+    if let Some(next_log)=self.next_latency_log {
+      if now>=next_log {
+        match self.pings.summary() {
+          Some(summary)=>println!("{}",summary),
+          None=>println!("no latency information yet"),
+        }
+        if let Some(loss)=self.sequence.summary() {
+          println!("{}",loss);
+        }
+        if let Some((recovered,missed))=self.samples.summary() {
+          println!("recovered {} samples from repeats, missed {}",recovered,missed);
+        }
+        if self.compact.skipped()>0 {
+          println!("skipped {} compact deltas missing the sample before them",self.compact.skipped());
+        }
+        if let Some(estimate)=self.clock.estimate() {
+          println!("{}",estimate);
+        }
+        if self.config.coalesce {
+          println!("merged {} samples into newer ones",self.merged);
+        }
+        if let Some(ref jitter)=self.jitter {
+          println!("jitter buffer delay {:.1}ms",jitter.delay()*1000.0);
+        }
+        if let Some(delay)=self.input_delay {
+          println!("input-to-cursor delay {:.1}ms",delay*1000.0);
+        }
+        self.next_latency_log=self.config.latency_log_interval.map(|secs| now+duration_from_secs(secs));
+      }
+    }
+    if let Some(next_keepalive)=self.next_keepalive {
+      if now>=next_keepalive {
+        self.send(&Packet::KeepAlive)?;
+      }
+    }
+    Ok(())
+  }
+  
+  ///Move to a stage, resetting the stage timeout.
+  fn set_stage(&mut self,stage: Stage) {
+    let timeout=match stage {
+      Stage::Disconnected=>None,
+      Stage::Connecting=>Some(self.config.handshake_timeout),
+      //Give up on the device after missing a few keepalives
+      Stage::Connected=>{
+        let keepalive_timeout=self.keepalive.map(|interval| {
+          secs_from_duration(interval) as f32*self.config.keepalive_misses.max(1) as f32
+        });
+        match (self.config.idle_timeout,keepalive_timeout) {
+          (Some(idle),Some(keepalive))=>Some(idle.min(keepalive)),
+          (idle,keepalive)=>idle.or(keepalive),
+        }
+      },
+    };
+    let now=Instant::now();
+    self.deadline=timeout.map(|secs| now+duration_from_secs(secs));
+    if stage!=self.stage {
+      println!("connection stage {} -> {}",self.stage,stage);
+      self.stage=stage;
+      //Timers only run while connected
+      self.pings.clear_pending();
+      self.contacts.clear();
+      if let Some(ref mut jitter)=self.jitter {
+        jitter.clear();
+      }
+      if let Some(ref mut setup)=self.setup {
+        setup.release_all();
+      }
+      //Every handshake starts a new device clock epoch
+      if stage==Stage::Connecting {
+        self.clock.reset();
+        self.sequence.reset();
+        self.samples.reset();
+        self.compact.reset();
+        self.input_delay=None;
+      }
+      if stage==Stage::Connected {
+        self.next_ping=self.config.ping_interval.map(|_| now);
+        self.next_latency_log=self.config.latency_log_interval.map(|secs| now+duration_from_secs(secs));
+        self.next_keepalive=self.keepalive.map(|interval| now+interval);
+      }else{
+        self.next_ping=None;
+        self.next_latency_log=None;
+        self.next_keepalive=None;
+      }
+    }
+  }
+  
+  ///Whether the packet belongs to an optional feature that both sides agreed on, if any.
+  ///Redundant samples are agreed on through the `redundancy` header instead of a capability.
+  fn is_agreed(&self,packet: &Packet)->bool {
+    match packet {
+      Packet::Redundant{..}=>self.redundancy.is_some(),
+      packet=>match packet.capability() {
+        Some(cap)=>self.capabilities.contains(cap),
+        None=>true,
+      },
+    }
+  }
+  
+  ///Replace the current server info, rebuilding the setup if the mapping is affected.
+  ///The minor version and capabilities are agreed on again with every server info.
+  fn replace_server_info(&mut self,server_info: ServerInfo) {
+    let version_changed=self.server_info.as_ref().map(|old| old.version)!=Some(server_info.version);
+    if version_changed {
+      self.minor=version::negotiate(ABSM_VERSION,server_info.version);
+      println!("using absM {}.{}",ABSM_VERSION.0,self.minor);
+    }
+    let remote_caps=match server_info.capabilities {
+      Some(ref caps)=>caps.clone(),
+      None=>version::implied_capabilities(self.minor),
+    };
+    let agreed=local_capabilities(&self.config).intersection(&remote_caps);
+    if agreed!=self.capabilities || self.server_info.is_none() {
+      println!("agreed capabilities: [{}]",agreed);
+      self.capabilities=agreed;
+    }
+    //Redundant samples are only sent if the server agreed to, with as many repeats as it chose
+    let redundancy=match (self.config.redundancy,server_info.redundancy) {
+      (Some(_),Some(redundancy))=>Some(redundancy),
+      _ => None,
+    };
+    if redundancy!=self.redundancy {
+      if let Some(redundancy)=redundancy {
+        println!("device repeats the previous {} samples in every packet",redundancy);
+      }
+      self.redundancy=redundancy;
+    }
+    //Keepalives are only used if both sides asked for them, at the interval chosen by the server
+    let keepalive=match (self.config.keepalive_interval,server_info.keepalive) {
+      (Some(_),Some(secs)) if secs>0.0=>Some(duration_from_secs(secs)),
+      _ => None,
+    };
+    if keepalive!=self.keepalive {
+      match keepalive {
+        Some(interval)=>println!("exchanging keepalives every {:?}",interval),
+        None=>println!("keepalives disabled"),
+      }
+      self.keepalive=keepalive;
+    }
+    let rebuild=match (&self.server_info,&self.setup) {
+      (Some(old),Some(_))=>{
+        let changes=old.mapping_changes(&server_info);
+        if changes.is_empty() {
+          println!("mapping unaffected by server info, keeping setup");
+        }else{
+          println!("server info changed the mapping:");
+          for change in &changes {
+            println!(" {}",change);
+          }
+        }
+        !changes.is_empty()
+      },
+      _ => true,
+    };
+    if rebuild {
+      println!("building setup");
+      if let Some(ref mut setup)=self.setup {
+        setup.release_all();
+      }
+      self.setup=Some(server_info.build(&self.config));
+    }
+    self.server_info=Some(server_info);
   }
 }
 
-#[derive(Deserialize,Serialize,Debug)]
+///The coordinate space of positions in device events, see the `'coordinate_space'` header.
+#[derive(Deserialize,Serialize,Copy,Clone,Debug,PartialEq,Eq)]
+pub enum CoordinateSpace {
+  ///Pixels, with `[0,0]` at the top-left corner and `+Y` down.
+  Pixels,
+  ///Pixels, with `[0,0]` at the center of the screen and `+Y` up.
+  Centered,
+}
+impl CoordinateSpace {
+  pub fn from_name(name: &[u8])->Option<CoordinateSpace> {
+    match name {
+      b"pixels"=>Some(CoordinateSpace::Pixels),
+      b"centered"=>Some(CoordinateSpace::Centered),
+      _ => None,
+    }
+  }
+  
+  ///Convert a position in this space into `Pixels` space, given the screen resolution.
+  pub fn to_pixels(self,pos: Pair<f32>,screen_res: Pair<f32>)->Pair<f32> {
+    match self {
+      CoordinateSpace::Pixels=>pos,
+      CoordinateSpace::Centered=>Pair([
+        pos[Axis::X]+screen_res[Axis::X]/2.0,
+        screen_res[Axis::Y]/2.0-pos[Axis::Y],
+      ]),
+    }
+  }
+}
+
+#[derive(Deserialize,Serialize,Debug,Clone,PartialEq)]
 pub struct ServerInfo {
   pub version: (u16,u16),
   pub server_screen_res: Pair<f32>,
+  ///Coordinate space of event positions, `Pixels` if not advertised.
+  pub coordinate_space: CoordinateSpace,
+  ///Optional features supported by the server, if advertised.
+  pub capabilities: Option<Capabilities>,
+  ///Keepalive interval chosen by the server in seconds, if it supports keepalives.
+  pub keepalive: Option<f32>,
+  ///How many previous samples the server repeats in `'rdnt'` packets, if it agreed to.
+  pub redundancy: Option<u8>,
+  ///A stable identifier for the device.
+  pub device_id: Option<String>,
+  ///Human readable device model name.
+  pub model: Option<String>,
+  ///Physical screen density, in dots per inch along each axis.
+  pub dpi: Option<Pair<f32>>,
+  ///Range of pressures reported by the device.
+  pub pressure_range: Option<[f32; 2]>,
+  ///Range of touch sizes reported by the device.
+  pub size_range: Option<[f32; 2]>,
+  ///Touch sample rate, in hertz.
+  pub sample_rate: Option<f32>,
+  ///Current screen rotation, in degrees clockwise from the natural orientation.
+  pub rotation: Option<u16>,
+  ///Screen area covered by system UI, as left, top, right and bottom insets in pixels.
+  pub safe_insets: Option<[f32; 4]>,
 }
 impl ServerInfo {
-  fn extend_from<'a>(&mut self,mut buf: &[u8],require_core_fields: bool) {
-    //Check packet header
-    assert!(buf.len()>=8,"server-info message too short");
-    assert!(&buf[0..4]==b"sInf","invalid server-info message");
-    let remote_version: (u16,u16)=network::decode_from(&buf[4..8]).unwrap();
-    assert!(
-      ABSM_VERSION.0==remote_version.0,
-      "abs-m protocol version mismatch: local {}.{} != remote {}.{}",
-      ABSM_VERSION.0,ABSM_VERSION.1 , remote_version.0,remote_version.1
+  ///Override the fields present in an `'sInf'` update, keeping the rest.
+  pub fn update(&mut self,version: (u16,u16),headers: &Headers)->Result<()> {
+    let mut builder=ServerInfoBuilder::from_info(self);
+    builder.extend(version,headers)?;
+    *self=builder.build()?;
+    Ok(())
+  }
+  ///Build server info from the handshake `'sInf'`, which must carry all required fields.
+  pub fn from_message(version: (u16,u16),headers: &Headers)->Result<ServerInfo> {
+    let mut builder=ServerInfoBuilder::new();
+    builder.extend(version,headers)?;
+    builder.build()
+  }
+  
+  ///Describe the differences with `new` in fields used to build the setup.
+  ///No differences means the setup can be kept as is.
+  pub fn mapping_changes(&self,new: &ServerInfo)->Vec<String> {
+    let mut changes=Vec::new();
+    if self.server_screen_res!=new.server_screen_res {
+      changes.push(format!("screen_res {} -> {}",self.server_screen_res,new.server_screen_res));
+    }
+    if self.coordinate_space!=new.coordinate_space {
+      changes.push(format!("coordinate_space {:?} -> {:?}",self.coordinate_space,new.coordinate_space));
+    }
+    if self.safe_insets!=new.safe_insets {
+      changes.push(format!("safe_insets {:?} -> {:?}",self.safe_insets,new.safe_insets));
+    }
+    if self.pressure_range!=new.pressure_range || self.size_range!=new.size_range {
+      changes.push("reported pressure or size range".to_string());
+    }
+    changes
+  }
+  
+  ///Log the device details known from the server info.
+  pub fn log_device(&self) {
+    println!(
+      "device {} (id {})",
+      self.model.as_ref().map_or("of unknown model",|model| &model[..]),
+      self.device_id.as_ref().map_or("unknown",|id| &id[..]),
     );
-    
-    //Core fields __must__ be set if `require_core_fields` is set
-    #[derive(Default)]
-    struct CoreFields {
-      screen_res: bool,
+    if let Some(dpi)=self.dpi {
+      println!("device screen density is {} dpi",dpi);
     }
-    let mut core=CoreFields::default();
+    if let Some(rate)=self.sample_rate {
+      println!("device samples touches at {}Hz",rate);
+    }
+    if let Some(rotation)=self.rotation {
+      println!("device screen is rotated {}°",rotation);
+    }
+    if let Some(range)=self.pressure_range {
+      println!("device reports pressures within {:?}",range);
+    }
+    if let Some(range)=self.size_range {
+      println!("device reports touch sizes within {:?}",range);
+    }
+    if let Some(insets)=self.safe_insets {
+      println!("device system UI insets (left, top, right, bottom) are {:?}",insets);
+    }
+  }
+  
+  pub fn build(&self,config: &Config)->Setup {
+    Setup::new(self,config)
+  }
+}
+
+///Collects `ServerInfo` fields from `'sInf'` headers.
+///Every field is stored as an `Option` until `build` is called.
+///Required fields must have been seen by then, while optional fields (those added in minor
+///versions) fall back to a default with `unwrap_or`.
+#[derive(Default,Debug)]
+pub struct ServerInfoBuilder {
+  version: Option<(u16,u16)>,
+  screen_res: Option<Pair<f32>>,
+  coordinate_space: Option<CoordinateSpace>,
+  capabilities: Option<Capabilities>,
+  keepalive: Option<f32>,
+  redundancy: Option<u8>,
+  device_id: Option<String>,
+  model: Option<String>,
+  dpi: Option<Pair<f32>>,
+  pressure_range: Option<[f32; 2]>,
+  size_range: Option<[f32; 2]>,
+  sample_rate: Option<f32>,
+  rotation: Option<u16>,
+  safe_insets: Option<[f32; 4]>,
+}
+impl ServerInfoBuilder {
+  pub fn new()->ServerInfoBuilder {ServerInfoBuilder::default()}
+  ///Start off with all fields seen, set to the values in `info`.
+  pub fn from_info(info: &ServerInfo)->ServerInfoBuilder {
+    ServerInfoBuilder{
+      version: Some(info.version),
+      screen_res: Some(info.server_screen_res),
+      coordinate_space: Some(info.coordinate_space),
+      capabilities: info.capabilities.clone(),
+      keepalive: info.keepalive,
+      redundancy: info.redundancy,
+      device_id: info.device_id.clone(),
+      model: info.model.clone(),
+      dpi: info.dpi,
+      pressure_range: info.pressure_range,
+      size_range: info.size_range,
+      sample_rate: info.sample_rate,
+      rotation: info.rotation,
+      safe_insets: info.safe_insets,
+    }
+  }
+  
+  ///Check the protocol version and record all known headers, logging unknown ones.
+  pub fn extend(&mut self,version: (u16,u16),headers: &Headers)->Result<()> {
+    //Check protocol version
+    if ABSM_VERSION.0!=version.0 {
+      return Err(Error::Version{local: ABSM_VERSION,remote: version});
+    }
+    self.version=Some(version);
     
-    //Search for headers
-    loop {
-      //Get the next header
-      let key;
-      let val;
-      if buf.len()==0 {
-        break;
-      }else{
-        let mut consume=|len| {
-          let slice=buf.get(..len).expect("malformed header fields");
-          buf=&buf[len..];
-          slice
-        };
-        let key_len: u32=network::decode_from(consume(4)).unwrap();
-        key=consume(key_len as usize);
-        let val_len: u32=network::decode_from(consume(4)).unwrap();
-        val=consume(val_len as usize);
-      }
-      
-      //Process key/value pair
+    //Process headers
+    for (key,val) in headers.iter() {
       match key {
-        b"screen_res"=>{
-          self.server_screen_res=network::decode_from(val).expect("screen_res header too short");
-          println!("server screen resolution is {}",self.server_screen_res);
-          core.screen_res=true;
+        b"screen_res"=>if let Some(screen_res)=headers.get("screen_res")? {
+          println!("server screen resolution is {}",screen_res);
+          self.screen_res=Some(screen_res);
         },
+        b"coordinate_space"=>match CoordinateSpace::from_name(val) {
+          Some(space)=>{
+            println!("server coordinate space is {:?}",space);
+            self.coordinate_space=Some(space);
+          },
+          None=>return Err(DecodeError::BadHeader{key: "coordinate_space".to_string()}.into()),
+        },
+        b"capabilities"=>{
+          let capabilities=Capabilities::parse(val);
+          println!("server capabilities are [{}]",capabilities);
+          self.capabilities=Some(capabilities);
+        },
+        b"keepalive"=>self.keepalive=headers.get("keepalive")?,
+        b"redundancy"=>self.redundancy=headers.get("redundancy")?,
+        b"device_id"=>self.device_id=Some(String::from_utf8_lossy(val).into_owned()),
+        b"model"=>self.model=Some(String::from_utf8_lossy(val).into_owned()),
+        b"dpi"=>self.dpi=headers.get("dpi")?,
+        b"pressure_range"=>self.pressure_range=headers.get("pressure_range")?,
+        b"size_range"=>self.size_range=headers.get("size_range")?,
+        b"sample_rate"=>self.sample_rate=headers.get("sample_rate")?,
+        b"rotation"=>self.rotation=headers.get("rotation")?,
+        b"safe_insets"=>self.safe_insets=headers.get("safe_insets")?,
         _=>{
           println!(
             "unknown server info header '{}' = '{}'",
@@ -154,33 +928,110 @@ impl ServerInfo {
         },
       }
     }
-    
-    //Check if core field requirements are met
-    if require_core_fields {
-      assert!(
-        core.screen_res,
-        "server-info is missing some core fields"
-      );
-    }
+    Ok(())
   }
   
-  pub fn update<'a>(&mut self,msg: &[u8]) {
-    self.extend_from(msg,false);
+  ///Build the server info, failing if any required field is missing.
+  pub fn build(&self)->Result<ServerInfo> {
+    fn required<T: Copy>(field: Option<T>,name: &str)->Result<T> {
+      field.ok_or_else(|| Error::Protocol(format!("server-info is missing required field '{}'",name)))
+    }
+    Ok(ServerInfo{
+      version: required(self.version,"version")?,
+      server_screen_res: required(self.screen_res,"screen_res")?,
+      coordinate_space: self.coordinate_space.unwrap_or(CoordinateSpace::Pixels),
+      capabilities: self.capabilities.clone(),
+      keepalive: self.keepalive,
+      redundancy: self.redundancy,
+      device_id: self.device_id.clone(),
+      model: self.model.clone(),
+      dpi: self.dpi,
+      pressure_range: self.pressure_range,
+      size_range: self.size_range,
+      sample_rate: self.sample_rate,
+      rotation: self.rotation,
+      safe_insets: self.safe_insets,
+    })
+  }
+}
+
+///Capabilities supported by this client over the configured remote.
+///Sequence numbers are only useful over UDP, where datagrams may be lost or reordered.
+fn local_capabilities(config: &Config)->Capabilities {
+  let mut caps=Capabilities::local();
+  if let Remote::Tcp(..)=config.remote {
+    caps=caps.without(Capability::Sequence);
   }
-  pub fn from_message<'a>(msg: &[u8])->ServerInfo {
-    let mut new: ServerInfo=unsafe{mem::uninitialized()};
-    new.extend_from(msg,true);
-    new
+  if !config.multitouch {
+    caps=caps.without(Capability::MultiTouch);
   }
-  
-  pub fn build(&self,config: &Config)->Setup {
-    Setup::new(&self,config)
+  //The jitter buffer needs timestamped samples, which compact touches would replace
+  if config.compact_touches && !config.jitter_buffer {
+    //Devices send batches whenever they can, so leave them out to get compact touches
+    caps=caps.without(Capability::Batch);
+  }else{
+    caps=caps.without(Capability::Compact);
   }
+  caps
+}
+
+///Whether an I/O error is caused by a read timeout.
+fn is_timeout(err: &io::Error)->bool {
+  err.kind()==io::ErrorKind::WouldBlock || err.kind()==io::ErrorKind::TimedOut
 }
 
-pub fn encode_header(buf: &mut Vec<u8>,key: &[u8],val: &[u8]) {
-  network::encode_into(&mut *buf,&(key.len() as u32)).unwrap();
-  buf.extend_from_slice(key);
-  network::encode_into(&mut *buf,&(val.len() as u32)).unwrap();
-  buf.extend_from_slice(val);
+///Encode and send a single packet through a connection.
+pub fn send_packet(conn: &mut dyn Connection,packet: &Packet)->Result<()> {
+  NET_BUFFER.borrow(|buf| {
+    packet.encode(&mut *buf);
+    conn.send(buf)
+  })
+}
+
+///Receive and decode a single packet from a connection, only if one has already arrived.
+pub fn try_recv_packet(conn: &mut dyn Connection)->Result<Option<Packet>> {
+  NET_BUFFER.borrow(|buf| {
+    if !conn.try_recv(&mut *buf)? {
+      return Ok(None);
+    }
+    Ok(Some(Packet::decode(buf)?))
+  })
+}
+
+///Receive and decode a single packet from a connection.
+pub fn recv_packet(conn: &mut dyn Connection)->Result<Packet> {
+  NET_BUFFER.borrow(|buf| {
+    conn.recv(&mut *buf)?;
+    Ok(Packet::decode(buf)?)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  ///Capabilities advertised by the reference device.
+  const DEVICE_CAPS: &[u8]=b"multitouch,batch,clock,sequence,compact";
+  
+  #[test]
+  fn touch_encoding_negotiation() {
+    let udp=Remote::Udp("localhost".into(),8517);
+    let table=[
+      //(compact_touches,jitter_buffer,batch,compact)
+      (false,false,true,false),
+      (false,true,true,false),
+      (true,false,false,true),
+      (true,true,true,false),
+    ];
+    for &(compact_touches,jitter_buffer,batch,compact) in table.iter() {
+      let config=Config{remote: udp.clone(),compact_touches,jitter_buffer,..Config::default()};
+      let agreed=local_capabilities(&config).intersection(&Capabilities::parse(DEVICE_CAPS));
+      assert_eq!(agreed.contains(Capability::Batch),batch,"{:?}",agreed);
+      assert_eq!(agreed.contains(Capability::Compact),compact,"{:?}",agreed);
+      assert!(agreed.contains(Capability::ClockSync) && agreed.contains(Capability::Sequence));
+    }
+    //The defaults keep timestamped batches
+    let agreed=local_capabilities(&Config::default()).intersection(&Capabilities::parse(DEVICE_CAPS));
+    assert!(agreed.contains(Capability::Batch) && !agreed.contains(Capability::Compact));
+  }
 }
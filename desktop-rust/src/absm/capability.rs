@@ -0,0 +1,107 @@
+use prelude::*;
+use std::{
+  collections::{BTreeSet},
+  iter::{FromIterator},
+};
+
+///An optional protocol feature, advertised in the `capabilities` handshake header.
+#[derive(Deserialize,Serialize,Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Capability {
+  ///`'mtch'` multi-touch packets.
+  MultiTouch,
+  ///`'pen '` stylus packets.
+  Pen,
+  ///`'btch'` batched sample packets.
+  Batch,
+  ///Device timestamps appended to `'repl'` packets.
+  ClockSync,
+  ///`'seqn'` sequence-numbered event packets.
+  Sequence,
+  ///`'cmov'` and `'dmov'` fixed-point touch packets.
+  Compact,
+}
+impl Capability {
+  pub const ALL: &'static [Capability]=&[
+    Capability::MultiTouch,
+    Capability::Pen,
+    Capability::Batch,
+    Capability::ClockSync,
+    Capability::Sequence,
+    Capability::Compact,
+  ];
+  
+  ///The name of this capability within the `capabilities` header.
+  pub fn name(self)->&'static str {
+    match self {
+      Capability::MultiTouch=>"multitouch",
+      Capability::Pen=>"pen",
+      Capability::Batch=>"batch",
+      Capability::ClockSync=>"clock",
+      Capability::Sequence=>"sequence",
+      Capability::Compact=>"compact",
+    }
+  }
+  pub fn from_name(name: &str)->Option<Capability> {
+    Capability::ALL.iter().cloned().find(|cap| cap.name()==name)
+  }
+}
+
+///A set of capabilities, encoded as a comma-separated list of names.
+#[derive(Deserialize,Serialize,Clone,Debug,Default,PartialEq)]
+pub struct Capabilities(BTreeSet<Capability>);
+impl Capabilities {
+  ///No optional features at all.
+  pub fn none()->Capabilities {Capabilities::default()}
+  ///Every capability supported by this client.
+  pub fn local()->Capabilities {
+    Capability::ALL.iter().cloned().collect()
+  }
+  
+  ///Parse a raw `capabilities` header value, logging and skipping unknown names.
+  pub fn parse(raw: &[u8])->Capabilities {
+    let mut caps=Capabilities::none();
+    for name in String::from_utf8_lossy(raw).split(',').map(str::trim).filter(|name| !name.is_empty()) {
+      match Capability::from_name(name) {
+        Some(cap)=>{caps.0.insert(cap);},
+        None=>println!("ignoring unknown capability '{}'",name),
+      }
+    }
+    caps
+  }
+  ///Encode as a raw `capabilities` header value.
+  pub fn encode(&self)->Vec<u8> {
+    self.to_string().into_bytes()
+  }
+  
+  ///This set, leaving out a single capability.
+  pub fn without(mut self,cap: Capability)->Capabilities {
+    self.0.remove(&cap);
+    self
+  }
+  
+  ///The capabilities supported by both sets.
+  pub fn intersection(&self,other: &Capabilities)->Capabilities {
+    Capabilities(self.0.intersection(&other.0).cloned().collect())
+  }
+  pub fn contains(&self,cap: Capability)->bool {
+    self.0.contains(&cap)
+  }
+}
+impl FromIterator<Capability> for Capabilities {
+  fn from_iter<I: IntoIterator<Item=Capability>>(iter: I)->Capabilities {
+    Capabilities(iter.into_iter().collect())
+  }
+}
+impl fmt::Display for Capabilities {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    let mut first=true;
+    for cap in self.0.iter() {
+      if !first {
+        f.write_str(",")?;
+      }
+      first=false;
+      f.write_str(cap.name())?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,217 @@
+use prelude::*;
+use std::{
+  collections::{VecDeque},
+  time::{Duration,Instant},
+};
+use network;
+
+///Get the device timestamp appended to a `'repl'` payload by the device, if any.
+///Reply payloads are the 12-byte ping payload followed by an 8-byte device timestamp.
+pub fn reply_timestamp(data: &[u8])->Option<u64> {
+  if data.len()<20 {
+    return None;
+  }
+  network::decode_from(&data[12..20]).ok()
+}
+
+///A single ping exchange, in seconds since the local epoch.
+#[derive(Copy,Clone,Debug)]
+struct Exchange {
+  ///Local time halfway between sending the ping and receiving its reply.
+  local: f64,
+  ///Device time minus local time.
+  offset: f64,
+  rtt: f64,
+}
+
+///Estimates the offset and drift of the device clock relative to the local clock, NTP-style.
+///Each ping exchange assumes the device timestamped its reply halfway through the round-trip,
+///so the offset of a single exchange is uncertain by half its round-trip time.
+///Exchanges with the lowest round-trip times are the most accurate, so only those are used.
+pub struct ClockSync {
+  ///Local times are measured from this instant.
+  epoch: Instant,
+  ///The latest exchanges, oldest first.
+  exchanges: VecDeque<Exchange>,
+  window: usize,
+}
+impl ClockSync {
+  ///Estimate using the latest `window` exchanges.
+  pub fn new(window: usize)->ClockSync {
+    ClockSync{
+      epoch: Instant::now(),
+      exchanges: VecDeque::with_capacity(window),
+      window: window.max(1),
+    }
+  }
+  
+  ///Forget all exchanges, for example because the device clock epoch changed.
+  pub fn reset(&mut self) {
+    self.exchanges.clear();
+  }
+  
+  ///Record a ping sent at `sent`, timestamped by the device at `device_time` and received back at `received`.
+  pub fn add_exchange(&mut self,sent: Instant,received: Instant,device_time: u64) {
+    let sent=secs_from_duration(sent.duration_since(self.epoch));
+    let received=secs_from_duration(received.duration_since(self.epoch));
+    let local=(sent+received)/2.0;
+    if self.exchanges.len()>=self.window {
+      self.exchanges.pop_front();
+    }
+    self.exchanges.push_back(Exchange{
+      local,
+      offset: device_time as f64*1e-6-local,
+      rtt: received-sent,
+    });
+  }
+  
+  ///The current estimate, if there have been any exchanges.
+  pub fn estimate(&self)->Option<ClockEstimate> {
+    let min_rtt=self.exchanges.iter().map(|ex| ex.rtt).fold(None,|min: Option<f64>,rtt| {
+      Some(min.map_or(rtt,|min| min.min(rtt)))
+    })?;
+    //Only keep exchanges close to the best round-trip time, slower ones were probably delayed on one way
+    let good: Vec<Exchange>=self.exchanges.iter()
+      .filter(|ex| ex.rtt<=min_rtt*2.0+0.001)
+      .cloned()
+      .collect();
+    let count=good.len() as f64;
+    let mean_local=good.iter().map(|ex| ex.local).sum::<f64>()/count;
+    let mean_offset=good.iter().map(|ex| ex.offset).sum::<f64>()/count;
+    //Least-squares fit of offset against local time, only when the exchanges span enough time
+    let spread: f64=good.iter().map(|ex| (ex.local-mean_local).powi(2)).sum();
+    let span=good.last().unwrap().local-good[0].local;
+    let drift=if good.len()>=3 && span>=1.0 {
+      good.iter().map(|ex| (ex.local-mean_local)*(ex.offset-mean_offset)).sum::<f64>()/spread
+    }else{
+      0.0
+    };
+    let residual=(good.iter()
+      .map(|ex| (ex.offset-mean_offset-drift*(ex.local-mean_local)).powi(2))
+      .sum::<f64>()/count).sqrt();
+    Some(ClockEstimate{
+      epoch: self.epoch,
+      reference: mean_local,
+      offset: mean_offset,
+      drift,
+      error: min_rtt/2.0+residual,
+      exchanges: good.len(),
+    })
+  }
+  
+  ///Convert a device timestamp, in microseconds, to a local instant using the current estimate.
+  ///Returns `None` if there is no estimate yet or the timestamp is before the local epoch.
+  pub fn device_to_local(&self,ts: u64)->Option<Instant> {
+    self.estimate()?.device_to_local(ts)
+  }
+}
+
+///An estimate of the device clock, relative to the local clock.
+#[derive(Copy,Clone,Debug)]
+pub struct ClockEstimate {
+  epoch: Instant,
+  ///Local time at which `offset` applies, in seconds since `epoch`.
+  reference: f64,
+  ///Device time minus local time at `reference`, in seconds.
+  pub offset: f64,
+  ///How many seconds the device clock gains per local second.
+  pub drift: f64,
+  ///Estimated error of converted timestamps, in seconds.
+  pub error: f64,
+  ///How many exchanges the estimate is based on.
+  pub exchanges: usize,
+}
+impl ClockEstimate {
+  ///Convert a device timestamp, in microseconds, to a local instant.
+  ///Returns `None` if the timestamp is before the local epoch.
+  pub fn device_to_local(&self,ts: u64)->Option<Instant> {
+    //device=local+offset+drift*(local-reference), solved for local
+    let device=ts as f64*1e-6;
+    let local=(device-self.offset+self.drift*self.reference)/(1.0+self.drift);
+    if local<0.0 || !local.is_finite() {
+      return None;
+    }
+    Some(self.epoch+Duration::new(local.trunc() as u64,(local.fract()*1e9) as u32))
+  }
+}
+impl fmt::Display for ClockEstimate {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    write!(
+      f,"device clock offset {:.1}ms, drift {:.1}ppm, error ±{:.1}ms ({} exchanges)",
+      self.offset*1000.0,self.drift*1e6,self.error*1000.0,self.exchanges
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  ///Local send time, round-trip time and how far through the round-trip the device stamped the
+  ///reply, all in seconds.
+  type Ping=(f64,f64,f64);
+  
+  ///Feed exchanges with the device clock following `device(local)`.
+  fn sync<F: Fn(f64)->f64>(exchanges: &[Ping],device: F)->ClockSync {
+    let mut sync=ClockSync::new(32);
+    let at=|secs: f64| sync.epoch+Duration::new(secs.trunc() as u64,(secs.fract()*1e9) as u32);
+    let exchanges: Vec<(Instant,Instant,u64)>=exchanges.iter().map(|&(sent,rtt,stamp)| {
+      (at(sent),at(sent+rtt),(device(sent+rtt*stamp)*1e6).round() as u64)
+    }).collect();
+    for (sent,received,device_time) in exchanges {
+      sync.add_exchange(sent,received,device_time);
+    }
+    sync
+  }
+  
+  #[test]
+  fn clock_estimates() {
+    let steady: Vec<Ping>=(0..10).map(|i| (1.0+i as f64,0.01,0.5)).collect();
+    let mut outlier=steady.clone();
+    //Delayed on the way back, so the device seems to have stamped it early
+    outlier.insert(5,(5.5,0.5,0.1));
+    //(exchanges,offset,drift,kept exchanges)
+    let table: Vec<(&[Ping],f64,f64,usize)>=vec![
+      (&steady,100.0,0.0,10),
+      (&steady,100.0,50e-6,10),
+      (&steady,100.0,-200e-6,10),
+      (&outlier,100.0,0.0,10),
+      (&outlier,100.0,50e-6,10),
+      (&steady[..2],100.0,0.0,2),
+    ];
+    for &(exchanges,offset,drift,kept) in table.iter() {
+      let device=|local: f64| local+offset+drift*local;
+      let sync=sync(exchanges,device);
+      let estimate=sync.estimate().unwrap();
+      assert_eq!(estimate.exchanges,kept);
+      assert!((estimate.drift-drift).abs()<1e-6,"{} drift, estimated {}",drift,estimate);
+      assert!(estimate.error>0.0049 && estimate.error<0.006,"estimated {}",estimate);
+      //Converting device timestamps back gives the local time they were taken at
+      for &local in [1.0,5.25,10.0,20.0].iter() {
+        let ts=(device(local)*1e6).round() as u64;
+        let converted=sync.device_to_local(ts).unwrap();
+        let secs=secs_from_duration(converted.duration_since(sync.epoch));
+        assert!((secs-local).abs()<1e-5,"{} converted to {} with {}",local,secs,estimate);
+      }
+    }
+  }
+  
+  #[test]
+  fn no_exchanges() {
+    let mut sync=ClockSync::new(4);
+    assert!(sync.estimate().is_none());
+    assert!(sync.device_to_local(1_000_000).is_none());
+    let now=sync.epoch+Duration::from_secs(1);
+    sync.add_exchange(now,now+Duration::from_millis(10),2_000_000);
+    assert!(sync.estimate().is_some());
+    sync.reset();
+    assert!(sync.estimate().is_none());
+  }
+  
+  #[test]
+  fn timestamps_before_epoch() {
+    let sync=sync(&[(1.0,0.01,0.5),(2.0,0.01,0.5)],|local| local+100.0);
+    assert!(sync.device_to_local(50_000_000).is_none());
+    assert!(sync.device_to_local(100_500_000).is_some());
+  }
+}
@@ -0,0 +1,165 @@
+use PenTool;
+use absm::packet::{Packet};
+use absm::touch::{Phase};
+use absm::sequence::{SequenceTracker,Arrival};
+
+///Identifies position updates that can stand in for each other.
+#[derive(Clone,Debug,PartialEq)]
+enum MergeKey {
+  Touch,
+  Samples,
+  ///Pen moves only merge while the pen keeps the same state.
+  Pen{touching: bool,buttons: u8,tool: PenTool},
+  ///Multi-touch moves only merge while the same pointers move.
+  Contacts(Vec<u32>),
+}
+
+///How a packet takes part in coalescing.
+#[derive(Clone,Debug,PartialEq)]
+enum Role {
+  ///Does not affect the cursor, so it neither merges nor separates moves.
+  Neutral,
+  ///A transition that must be applied, after the moves before it and before the moves after it.
+  Barrier,
+  ///A position update, superseded by the next one with the same key.
+  Move(MergeKey),
+}
+
+fn role(packet: &Packet)->Role {
+  match packet {
+    Packet::Touch(..)=>Role::Move(MergeKey::Touch),
+    Packet::Batch(..)=>Role::Move(MergeKey::Samples),
+    Packet::Pen(pen)=>Role::Move(MergeKey::Pen{touching: pen.touching,buttons: pen.buttons,tool: pen.tool}),
+    Packet::MultiTouch(contacts)=>{
+      if contacts.iter().all(|contact| contact.phase==Phase::Move) {
+        Role::Move(MergeKey::Contacts(contacts.iter().map(|contact| contact.id).collect()))
+      }else{
+        Role::Barrier
+      }
+    },
+    Packet::Ping(..) | Packet::Reply(..) | Packet::KeepAlive | Packet::Unknown{..}=>Role::Neutral,
+    _ => Role::Barrier,
+  }
+}
+
+///How many samples a position update carries.
+fn sample_count(packet: &Packet)->usize {
+  match packet {
+    Packet::Batch(samples)=>samples.len(),
+    Packet::MultiTouch(contacts)=>contacts.len(),
+    _ => 1,
+  }
+}
+
+///Keep only the last sample of a batch, returning how many were dropped.
+fn trim_batch(packet: &mut Packet)->usize {
+  match packet {
+    Packet::Batch(samples) if samples.len()>1=>{
+      let merged=samples.len()-1;
+      samples.drain(..merged);
+      merged
+    },
+    _ => 0,
+  }
+}
+
+///The result of coalescing a run of packets.
+pub struct Coalesced {
+  ///Packets to apply, in order.
+  pub packets: Vec<Packet>,
+  ///How many samples were merged into newer ones.
+  pub merged: usize,
+}
+
+///Sort out `'seqn'` packets in the order they arrived, before merging any of them, so that
+///merging can't make an older packet look late.
+///Fresh packets are unwrapped and duplicates dropped.
+///Late packets stay wrapped so that they are handled as late, unless they only carry positions,
+///which newer ones already superseded.
+pub fn unwrap_sequenced(packets: Vec<Packet>,tracker: &mut SequenceTracker)->Vec<Packet> {
+  packets.into_iter().filter_map(|packet| match packet {
+    Packet::Sequenced{seq,packet}=>match tracker.on_packet(seq) {
+      Arrival::Fresh=>Some(*packet),
+      Arrival::Late if packet.is_position()=>None,
+      Arrival::Late=>Some(Packet::Sequenced{seq,packet}),
+      Arrival::Duplicate=>None,
+    },
+    packet=>Some(packet),
+  }).collect()
+}
+
+///Merge position updates that were superseded by a newer one before any transition, such as a
+///touch going down or up or a key event.
+pub fn coalesce(packets: Vec<Packet>)->Coalesced {
+  let roles: Vec<Role>=packets.iter().map(role).collect();
+  let mut out=Coalesced{packets: Vec::with_capacity(packets.len()),merged: 0};
+  for (idx,mut packet) in packets.into_iter().enumerate() {
+    if let Role::Move(ref key)=roles[idx] {
+      //Superseded if the next packet affecting the cursor is a move with the same key
+      let next=roles[idx+1..].iter().find(|role| **role!=Role::Neutral);
+      if next==Some(&Role::Move(key.clone())) {
+        out.merged+=sample_count(&packet);
+        continue;
+      }
+      out.merged+=trim_batch(&mut packet);
+    }
+    out.packets.push(packet);
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rect::{Pair,Axis};
+  use {MouseMove,KeyPress};
+  
+  fn touch(seq: u32,x: f32)->Packet {
+    let ev=MouseMove{pos: Pair([x,0.0]),pressure: 1.0,size: 0.0};
+    Packet::Sequenced{seq,packet: Box::new(Packet::Touch(ev))}
+  }
+  
+  fn key(seq: u32)->Packet {
+    let ev=KeyPress{key: "a".into(),scancode: "a".into(),down: false,modifiers: 0};
+    Packet::Sequenced{seq,packet: Box::new(Packet::Key(ev))}
+  }
+  
+  ///Describe packets as their type and, for touches, their X coordinate.
+  fn describe(packets: &[Packet])->Vec<String> {
+    packets.iter().map(|packet| match packet {
+      Packet::Touch(ev)=>format!("mmov {}",ev.pos[Axis::X]),
+      Packet::Sequenced{seq,packet}=>format!("late {} {}",seq,String::from_utf8_lossy(&packet.ty())),
+      packet=>String::from_utf8_lossy(&packet.ty()).into_owned(),
+    }).collect()
+  }
+  
+  #[test]
+  fn coalesce_sequenced_packets() {
+    //Each row is the packets waiting, the packets left to apply and how many were merged
+    let table: Vec<(Vec<Packet>,Vec<&str>,usize)>=vec![
+      //The touch before the key event is kept, only the one after it is merged
+      (vec![touch(1,1.0),key(2),touch(3,3.0),touch(4,4.0)],vec!["mmov 1","keyp","mmov 4"],1),
+      //Duplicates never stand in for newer packets, late positions are dropped before merging
+      (
+        vec![touch(6,6.0),touch(6,6.0),touch(5,5.0),key(4),touch(7,7.0),touch(8,8.0)],
+        vec!["mmov 6","late 4 keyp","mmov 8"],
+        1,
+      ),
+      (vec![touch(10,10.0),touch(11,11.0),touch(12,12.0)],vec!["mmov 12"],2),
+    ];
+    for (waiting,expected,merged) in table {
+      let mut tracker=SequenceTracker::new();
+      let coalesced=coalesce(unwrap_sequenced(waiting,&mut tracker));
+      assert_eq!(describe(&coalesced.packets),expected);
+      assert_eq!(coalesced.merged,merged);
+      //Every packet counts as received, in the order it arrived
+      let summary=tracker.summary().unwrap();
+      assert_eq!(summary.lost,0,"{:?}",expected);
+    }
+    //Late and duplicated packets are counted once
+    let mut tracker=SequenceTracker::new();
+    unwrap_sequenced(vec![touch(6,6.0),touch(6,6.0),touch(5,5.0),key(4),touch(7,7.0)],&mut tracker);
+    let summary=tracker.summary().unwrap();
+    assert_eq!((summary.received,summary.late,summary.duplicates),(4,2,1));
+  }
+}
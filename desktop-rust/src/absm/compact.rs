@@ -0,0 +1,370 @@
+use rect::{Pair,Axis};
+use MouseMove;
+use absm::{CoordinateSpace,ServerInfo};
+
+///A touch sample in fixed point, as carried by `'cmov'` packets.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct CompactSample {
+  ///X and Y, where 0 and 65535 are the edges of the screen.
+  pub pos: [u16; 2],
+  ///Pressure, where 0 and 255 are the ends of the pressure range.
+  pub pressure: u8,
+  ///Size, where 0 and 255 are the ends of the size range.
+  pub size: u8,
+}
+
+///The change from the previous sample, as carried by `'dmov'` packets.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct CompactDelta {
+  pub pos: [i8; 2],
+  pub pressure: i8,
+  pub size: i8,
+}
+impl CompactDelta {
+  ///Apply to the previous sample, unless the result is out of range.
+  pub fn apply(self,prev: CompactSample)->Option<CompactSample> {
+    fn add(val: i32,delta: i8,max: i32)->Option<i32> {
+      let val=val+delta as i32;
+      if val>=0 && val<=max {Some(val)}else{None}
+    }
+    Some(CompactSample{
+      pos: [
+        add(prev.pos[0] as i32,self.pos[0],0xffff)? as u16,
+        add(prev.pos[1] as i32,self.pos[1],0xffff)? as u16,
+      ],
+      pressure: add(prev.pressure as i32,self.pressure,0xff)? as u8,
+      size: add(prev.size as i32,self.size,0xff)? as u8,
+    })
+  }
+}
+
+///A compact touch packet.
+///Samples are numbered consecutively, so that a delta can tell whether the sample it refers to
+///was received.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum CompactTouch {
+  ///`'cmov'`, a whole sample.
+  Absolute{num: u8,sample: CompactSample},
+  ///`'dmov'`, a sample given as the change from sample `num-1`.
+  Delta{num: u8,delta: CompactDelta},
+}
+impl CompactTouch {
+  pub fn num(self)->u8 {
+    match self {
+      CompactTouch::Absolute{num,..} | CompactTouch::Delta{num,..}=>num,
+    }
+  }
+}
+
+///Converts fixed-point samples to touch events, given the screen and ranges the server reported.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct CompactScale {
+  ///Coordinates of the screen edges, at fixed-point 0 and 65535.
+  min: Pair<f32>,
+  max: Pair<f32>,
+  pressure: [f32; 2],
+  size: [f32; 2],
+}
+impl CompactScale {
+  ///Pressure and size default to the `[0,1]` range when the server reports none.
+  pub fn new(info: &ServerInfo)->CompactScale {
+    let res=info.server_screen_res;
+    let (min,max)=match info.coordinate_space {
+      CoordinateSpace::Pixels=>(Pair([0.0,0.0]),res),
+      CoordinateSpace::Centered=>(
+        Pair([-res[Axis::X]/2.0,-res[Axis::Y]/2.0]),
+        Pair([res[Axis::X]/2.0,res[Axis::Y]/2.0]),
+      ),
+    };
+    CompactScale{
+      min,
+      max,
+      pressure: info.pressure_range.unwrap_or([0.0,1.0]),
+      size: info.size_range.unwrap_or([0.0,1.0]),
+    }
+  }
+  
+  pub fn decode(&self,sample: CompactSample)->MouseMove {
+    fn lerp(range: [f32; 2],val: u16,max: u16)->f32 {
+      range[0]+(range[1]-range[0])*(val as f32/max as f32)
+    }
+    MouseMove{
+      pos: Pair([
+        lerp([self.min[Axis::X],self.max[Axis::X]],sample.pos[0],0xffff),
+        lerp([self.min[Axis::Y],self.max[Axis::Y]],sample.pos[1],0xffff),
+      ]),
+      pressure: lerp(self.pressure,sample.pressure as u16,0xff),
+      size: lerp(self.size,sample.size as u16,0xff),
+    }
+  }
+}
+
+///Rebuilds whole samples from `'cmov'` and `'dmov'` packets.
+#[derive(Default)]
+pub struct CompactDecoder {
+  ///Number and value of the last sample rebuilt, unless a delta could not be applied since.
+  last: Option<(u8,CompactSample)>,
+  ///Deltas dropped because the sample they refer to was missing.
+  skipped: u64,
+}
+impl CompactDecoder {
+  pub fn new()->CompactDecoder {CompactDecoder::default()}
+  
+  ///Forget the last sample and statistics, for example because the remote restarted its
+  ///numbering.
+  pub fn reset(&mut self) {
+    *self=CompactDecoder::default();
+  }
+  
+  ///Rebuild the sample carried by a packet.
+  ///Returns `None` for a delta against a sample that was not received, and for every delta
+  ///after it until the next absolute sample.
+  pub fn decode(&mut self,touch: CompactTouch)->Option<CompactSample> {
+    let sample=match touch {
+      CompactTouch::Absolute{sample,..}=>Some(sample),
+      CompactTouch::Delta{num,delta}=>match self.last {
+        Some((last,prev)) if num==last.wrapping_add(1)=>delta.apply(prev),
+        _ => None,
+      },
+    };
+    match sample {
+      Some(sample)=>self.last=Some((touch.num(),sample)),
+      None=>{
+        self.last=None;
+        self.skipped+=1;
+      },
+    }
+    sample
+  }
+  
+  ///How many deltas were dropped so far.
+  pub fn skipped(&self)->u64 {
+    self.skipped
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use absm::ABSM_VERSION;
+  use absm::packet::{Packet,Headers};
+  
+  fn info(res: [f32; 2],space: &str)->ServerInfo {
+    let mut headers=Headers::new();
+    headers.insert("screen_res",&res);
+    headers.insert_raw("coordinate_space",space);
+    ServerInfo::from_message(ABSM_VERSION,&headers).unwrap()
+  }
+  
+  ///Quantize a touch event the way the reference device does.
+  fn encode(scale: &CompactScale,ev: &MouseMove)->CompactSample {
+    fn quantize(range: [f32; 2],val: f32,max: u16)->u16 {
+      let t=((val-range[0])/(range[1]-range[0])).clamp(0.0,1.0);
+      (t*max as f32).round() as u16
+    }
+    CompactSample{
+      pos: [
+        quantize([scale.min[Axis::X],scale.max[Axis::X]],ev.pos[Axis::X],0xffff),
+        quantize([scale.min[Axis::Y],scale.max[Axis::Y]],ev.pos[Axis::Y],0xffff),
+      ],
+      pressure: quantize(scale.pressure,ev.pressure,0xff) as u8,
+      size: quantize(scale.size,ev.size,0xff) as u8,
+    }
+  }
+  
+  ///Send a delta when it fits, an absolute sample otherwise.
+  fn encode_stream(samples: &[CompactSample])->Vec<CompactTouch> {
+    let mut prev: Option<CompactSample>=None;
+    samples.iter().enumerate().map(|(idx,&sample)| {
+      let num=idx as u8;
+      let delta=prev.and_then(|prev| {
+        fn diff(a: i32,b: i32)->Option<i8> {
+          let d=b-a;
+          if (-128..=127).contains(&d) {Some(d as i8)}else{None}
+        }
+        Some(CompactDelta{
+          pos: [
+            diff(prev.pos[0] as i32,sample.pos[0] as i32)?,
+            diff(prev.pos[1] as i32,sample.pos[1] as i32)?,
+          ],
+          pressure: diff(prev.pressure as i32,sample.pressure as i32)?,
+          size: diff(prev.size as i32,sample.size as i32)?,
+        })
+      });
+      prev=Some(sample);
+      match delta {
+        Some(delta)=>CompactTouch::Delta{num,delta},
+        None=>CompactTouch::Absolute{num,sample},
+      }
+    }).collect()
+  }
+  
+  ///Pack touches into `'seqn'` packets byte by byte, the way `send_compact` in the reference
+  ///device does.
+  fn device_stream(width: f32,height: f32,touches: &[MouseMove])->Vec<Vec<u8>> {
+    fn quantize(val: f32,range: f32,steps: u16)->i32 {
+      ((val/range).clamp(0.0,1.0)*steps as f32+0.5).floor() as i32
+    }
+    let mut prev: Option<[i32; 4]>=None;
+    let mut since=0;
+    touches.iter().enumerate().map(|(idx,ev)| {
+      let sample=[
+        quantize(ev.pos[Axis::X],width,0xffff),quantize(ev.pos[Axis::Y],height,0xffff),
+        quantize(ev.pressure,1.0,0xff),quantize(ev.size,1.0,0xff),
+      ];
+      let mut buf=b"seqn".to_vec();
+      buf.extend_from_slice(&(idx as u32).to_be_bytes());
+      let delta: Option<Vec<i32>>=prev.filter(|_| since<16).map(|prev| {
+        (0..4).map(|i| sample[i]-prev[i]).collect()
+      }).filter(|delta: &Vec<i32>| delta.iter().all(|d| (-128..=127).contains(d)));
+      prev=Some(sample);
+      match delta {
+        Some(delta)=>{
+          since+=1;
+          buf.extend_from_slice(b"dmov");
+          buf.push(idx as u8);
+          buf.extend(delta.iter().map(|&d| d as i8 as u8));
+        },
+        None=>{
+          since=1;
+          buf.extend_from_slice(b"cmov");
+          buf.push(idx as u8);
+          buf.extend_from_slice(&(sample[0] as u16).to_be_bytes());
+          buf.extend_from_slice(&(sample[1] as u16).to_be_bytes());
+          buf.push(sample[2] as u8);
+          buf.push(sample[3] as u8);
+        },
+      }
+      buf
+    }).collect()
+  }
+  
+  #[test]
+  fn device_byte_stream() {
+    let res=[1080.0,2400.0];
+    let scale=CompactScale::new(&info(res,"pixels"));
+    //A slow swipe across the screen with a jump in the middle, longer than the refresh interval
+    let touches: Vec<MouseMove>=(0..300).map(|i| {
+      let jump=if i>=150 {300.0}else{0.0};
+      MouseMove{
+        pos: Pair([100.0+i as f32*1.7+jump,2000.0-i as f32*3.3]),
+        pressure: 0.5+(i%7) as f32*0.01,
+        size: 0.1,
+      }
+    }).collect();
+    let stream=device_stream(res[0],res[1],&touches);
+    let mut decoder=CompactDecoder::new();
+    let mut absolute=0;
+    for (idx,(buf,ev)) in stream.iter().zip(touches.iter()).enumerate() {
+      let touch=match Packet::decode(buf) {
+        Ok(Packet::Sequenced{seq,packet})=>{
+          assert_eq!(seq,idx as u32);
+          match *packet {
+            Packet::Compact(touch)=>touch,
+            other=>panic!("sample {} decoded as {:?}",idx,other),
+          }
+        },
+        other=>panic!("sample {} decoded as {:?}",idx,other),
+      };
+      if let CompactTouch::Absolute{..}=touch {
+        absolute+=1;
+      }
+      let decoded=scale.decode(decoder.decode(touch).expect("delta was not applied"));
+      assert!((decoded.pos[Axis::X]-ev.pos[Axis::X]).abs()<=0.01,"{:?} -> {:?}",ev,decoded);
+      assert!((decoded.pos[Axis::Y]-ev.pos[Axis::Y]).abs()<=0.02,"{:?} -> {:?}",ev,decoded);
+      assert!((decoded.pressure-ev.pressure).abs()<=0.002,"{:?} -> {:?}",ev,decoded);
+    }
+    //An absolute sample every 16, plus one for the jump
+    assert_eq!(absolute,300/16+1+1);
+    assert_eq!(decoder.skipped(),0);
+  }
+  
+  #[test]
+  fn packet_round_trip() {
+    let table=[
+      CompactTouch::Absolute{num: 0,sample: CompactSample{pos: [0,0xffff],pressure: 0,size: 0xff}},
+      CompactTouch::Absolute{num: 0xff,sample: CompactSample{pos: [0x1234,0x8000],pressure: 128,size: 7}},
+      CompactTouch::Delta{num: 1,delta: CompactDelta{pos: [-128,127],pressure: -1,size: 0}},
+      CompactTouch::Delta{num: 200,delta: CompactDelta{pos: [0,-5],pressure: 127,size: -128}},
+    ];
+    for &touch in table.iter() {
+      let mut buf=Vec::new();
+      Packet::Compact(touch).encode(&mut buf);
+      match Packet::decode(&buf) {
+        Ok(Packet::Compact(decoded))=>assert_eq!(decoded,touch),
+        other=>panic!("{:?} decoded as {:?}",touch,other),
+      }
+      assert!(Packet::decode(&buf[..buf.len()-1]).is_err(),"truncated {:?} decoded",touch);
+    }
+  }
+  
+  #[test]
+  fn quantization_precision() {
+    let table=[
+      ([1920.0,1080.0],"pixels"),
+      ([7680.0,4320.0],"pixels"),
+      ([1080.0,2400.0],"centered"),
+    ];
+    for &(res,space) in table.iter() {
+      let scale=CompactScale::new(&info(res,space));
+      //Half a fixed-point step, plus some leeway for float rounding
+      let max_err=[res[0]/0xffff as f32/2.0*1.01,res[1]/0xffff as f32/2.0*1.01];
+      let max_unit_err=1.0/0xff as f32/2.0*1.01;
+      for i in 0..=100 {
+        let t=i as f32/100.0;
+        let pos=Pair([
+          scale.min[Axis::X]+(scale.max[Axis::X]-scale.min[Axis::X])*t,
+          scale.min[Axis::Y]+(scale.max[Axis::Y]-scale.min[Axis::Y])*(1.0-t*t),
+        ]);
+        let ev=MouseMove{pos,pressure: t,size: 1.0-t};
+        let decoded=scale.decode(encode(&scale,&ev));
+        assert!((decoded.pos[Axis::X]-ev.pos[Axis::X]).abs()<=max_err[0],"{:?} -> {:?}",ev,decoded);
+        assert!((decoded.pos[Axis::Y]-ev.pos[Axis::Y]).abs()<=max_err[1],"{:?} -> {:?}",ev,decoded);
+        assert!((decoded.pressure-ev.pressure).abs()<=max_unit_err,"{:?} -> {:?}",ev,decoded);
+        assert!((decoded.size-ev.size).abs()<=max_unit_err,"{:?} -> {:?}",ev,decoded);
+      }
+      //Screen edges are exact
+      for &edge in [scale.min,scale.max].iter() {
+        let ev=MouseMove{pos: edge,pressure: 1.0,size: 0.0};
+        let decoded=scale.decode(encode(&scale,&ev));
+        assert_eq!(decoded.pos,edge);
+      }
+    }
+  }
+  
+  #[test]
+  fn delta_stream_round_trip() {
+    //A slow drag, then a jump too large for a delta, then a slow drag again
+    let samples: Vec<CompactSample>=(0..40u16).map(|i| {
+      let jump=if i>=20 {30000}else{0};
+      CompactSample{pos: [1000+i*100+jump,60000-i*37],pressure: (i*3) as u8,size: 50}
+    }).collect();
+    let stream=encode_stream(&samples);
+    assert!(matches!(stream[20],CompactTouch::Absolute{..}));
+    assert_eq!(stream.iter().filter(|touch| matches!(touch,CompactTouch::Delta{..})).count(),38);
+    let mut decoder=CompactDecoder::new();
+    let decoded: Vec<Option<CompactSample>>=stream.iter().map(|&touch| decoder.decode(touch)).collect();
+    let expected: Vec<Option<CompactSample>>=samples.iter().cloned().map(Some).collect();
+    assert_eq!(decoded,expected);
+    assert_eq!(decoder.skipped(),0);
+  }
+  
+  #[test]
+  fn delta_after_lost_sample() {
+    let samples: Vec<CompactSample>=(0..30u16).map(|i| {
+      let jump=if i>=20 {30000}else{0};
+      CompactSample{pos: [i*10+jump,i*10],pressure: 0,size: 0}
+    }).collect();
+    let stream=encode_stream(&samples);
+    let mut decoder=CompactDecoder::new();
+    for (idx,&touch) in stream.iter().enumerate() {
+      //Sample 5 is lost, deltas can't be applied until the absolute sample 20
+      if idx==5 {
+        continue;
+      }
+      let expected=if idx>5 && idx<20 {None}else{Some(samples[idx])};
+      assert_eq!(decoder.decode(touch),expected,"sample {}",idx);
+    }
+    assert_eq!(decoder.skipped(),14);
+  }
+}
@@ -0,0 +1,169 @@
+use prelude::*;
+use std::{
+  collections::{VecDeque},
+  time::{Duration,Instant},
+};
+use absm::touch::{Sample};
+
+///Holds timestamped samples for a short delay and releases them with their original spacing,
+///smoothing out bursty delivery.
+///Each sample is released `delay` seconds after the earliest it could have arrived, going by
+///the fastest transit time (local arrival time minus device timestamp) seen recently.
+pub struct JitterBuffer {
+  ///Local times are measured from this instant.
+  epoch: Instant,
+  ///Samples waiting to be released, with their release times, in order.
+  queue: VecDeque<(f64,Sample)>,
+  ///Transit times of the latest samples, in seconds, oldest first.
+  transits: VecDeque<f64>,
+  window: usize,
+  ///Fixed delay in seconds, or `None` to adapt to the observed jitter.
+  fixed_delay: Option<f32>,
+  ///Upper bound on the adaptive delay, in seconds.
+  max_delay: f32,
+  ///Release time of the last sample queued, to keep releases in order.
+  last_release: f64,
+}
+impl JitterBuffer {
+  pub fn new(fixed_delay: Option<f32>,max_delay: f32)->JitterBuffer {
+    JitterBuffer{
+      epoch: Instant::now(),
+      queue: VecDeque::new(),
+      transits: VecDeque::new(),
+      window: 256,
+      fixed_delay,
+      max_delay,
+      last_release: 0.0,
+    }
+  }
+  
+  ///Forget all samples and transit times, for example because the device clock epoch changed.
+  pub fn clear(&mut self) {
+    self.queue.clear();
+    self.transits.clear();
+    self.last_release=0.0;
+  }
+  
+  ///Queue a sample that arrived at `arrival`.
+  pub fn push(&mut self,sample: Sample,arrival: Instant) {
+    let transit=secs_from_duration(arrival.duration_since(self.epoch))-sample.time as f64*1e-6;
+    if self.transits.len()>=self.window {
+      self.transits.pop_front();
+    }
+    self.transits.push_back(transit);
+    let base=self.transits.iter().cloned().fold(f64::INFINITY,f64::min);
+    let release=(sample.time as f64*1e-6+base+self.delay() as f64).max(self.last_release);
+    self.last_release=release;
+    self.queue.push_back((release,sample));
+  }
+  
+  ///The current delay, in seconds.
+  ///The adaptive delay covers 95% of the transit time variation seen recently.
+  pub fn delay(&self)->f32 {
+    if let Some(delay)=self.fixed_delay {
+      return delay;
+    }
+    if self.transits.is_empty() {
+      return 0.0;
+    }
+    let mut sorted: Vec<f64>=self.transits.iter().cloned().collect();
+    sorted.sort_by(|a,b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+    let spread=sorted[((sorted.len()-1) as f64*0.95).round() as usize]-sorted[0];
+    (spread as f32).min(self.max_delay)
+  }
+  
+  ///When the next sample is due, if any is queued.
+  pub fn next_release(&self)->Option<Instant> {
+    self.queue.front().map(|&(release,_)| {
+      self.epoch+Duration::new(release.max(0.0).trunc() as u64,(release.max(0.0).fract()*1e9) as u32)
+    })
+  }
+  
+  ///Take every sample due by `now`, in order.
+  pub fn pop_due(&mut self,now: Instant)->Vec<Sample> {
+    let now=secs_from_duration(now.duration_since(self.epoch));
+    let mut due=Vec::new();
+    while let Some(&(release,_))=self.queue.front() {
+      if release>now {
+        break;
+      }
+      due.extend(self.queue.pop_front().map(|(_,sample)| sample));
+    }
+    due
+  }
+  
+  ///Take every queued sample, due or not.
+  pub fn drain(&mut self)->Vec<Sample> {
+    self.queue.drain(..).map(|(_,sample)| sample).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rect::{Pair};
+  use MouseMove;
+  
+  fn sample(millis: u64)->Sample {
+    Sample{time: millis*1000,ev: MouseMove{pos: Pair([0.0,0.0]),pressure: 1.0,size: 0.0}}
+  }
+  
+  fn at(jitter: &JitterBuffer,millis: u64)->Instant {
+    jitter.epoch+Duration::from_millis(millis)
+  }
+  
+  fn times(samples: Vec<Sample>)->Vec<u64> {
+    samples.into_iter().map(|sample| sample.time/1000).collect()
+  }
+  
+  #[test]
+  fn burst_is_released_at_original_spacing() {
+    let mut jitter=JitterBuffer::new(Some(0.05),0.1);
+    //The first sample arrives quickly, the rest in a late burst
+    jitter.push(sample(0),at(&jitter,1000));
+    for &time in [10,20,30].iter() {
+      jitter.push(sample(time),at(&jitter,1040));
+    }
+    assert_eq!(times(jitter.pop_due(at(&jitter,1049))),Vec::<u64>::new());
+    assert_eq!(times(jitter.pop_due(at(&jitter,1051))),vec![0]);
+    assert_eq!(times(jitter.pop_due(at(&jitter,1061))),vec![10]);
+    let next=jitter.next_release().unwrap();
+    assert!(next>at(&jitter,1069) && next<at(&jitter,1071));
+    assert_eq!(times(jitter.pop_due(at(&jitter,2000))),vec![20,30]);
+    assert!(jitter.next_release().is_none());
+  }
+  
+  #[test]
+  fn late_samples_keep_their_order() {
+    let mut jitter=JitterBuffer::new(Some(0.05),0.1);
+    jitter.push(sample(50),at(&jitter,1000));
+    //An older timestamp arriving afterwards, and a sample faster than any before
+    jitter.push(sample(20),at(&jitter,1001));
+    jitter.push(sample(60),at(&jitter,990));
+    jitter.push(sample(100),at(&jitter,1050));
+    //None may overtake a sample queued before it
+    assert_eq!(times(jitter.pop_due(at(&jitter,1049))),Vec::<u64>::new());
+    assert_eq!(times(jitter.pop_due(at(&jitter,1051))),vec![50,20,60]);
+    assert_eq!(times(jitter.drain()),vec![100]);
+    assert!(jitter.next_release().is_none());
+  }
+  
+  #[test]
+  fn adaptive_delay_follows_jitter() {
+    let mut jitter=JitterBuffer::new(None,0.1);
+    assert_eq!(jitter.delay(),0.0);
+    //Transit times vary between 0 and 9ms
+    for i in 0..100 {
+      jitter.push(sample(i*10),at(&jitter,1000+i*10+i%10));
+    }
+    assert!((jitter.delay()-0.009).abs()<0.0005,"delay {}",jitter.delay());
+    let mut capped=JitterBuffer::new(None,0.005);
+    for i in 0..100 {
+      capped.push(sample(i*10),at(&capped,1000+i*10+i%10));
+    }
+    assert_eq!(capped.delay(),0.005);
+    jitter.clear();
+    assert_eq!(jitter.delay(),0.0);
+    assert!(jitter.next_release().is_none());
+  }
+}
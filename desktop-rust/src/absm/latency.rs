@@ -0,0 +1,196 @@
+use prelude::*;
+use std::{
+  collections::{VecDeque},
+  time::{Duration,Instant},
+};
+use network;
+
+///Keeps track of `'ping'` packets sent to the device and the round-trip times of their replies.
+pub struct PingTracker {
+  ///Ping timestamps are measured from this instant.
+  epoch: Instant,
+  next_seq: u32,
+  ///Pings without a reply yet, oldest first.
+  pending: VecDeque<(u32,Instant)>,
+  ///Round-trip times of the latest replies, in seconds, oldest first.
+  rtts: VecDeque<f32>,
+  window: usize,
+}
+impl PingTracker {
+  ///Keep statistics over the latest `window` replies.
+  pub fn new(window: usize)->PingTracker {
+    PingTracker{
+      epoch: Instant::now(),
+      next_seq: 0,
+      pending: VecDeque::new(),
+      rtts: VecDeque::with_capacity(window),
+      window: window.max(1),
+    }
+  }
+  
+  ///Build the payload for a new ping sent at `now`.
+  ///The payload is a 4-byte sequence number followed by an 8-byte microsecond timestamp.
+  pub fn make_ping(&mut self,now: Instant)->Vec<u8> {
+    let seq=self.next_seq;
+    self.next_seq=self.next_seq.wrapping_add(1);
+    self.pending.push_back((seq,now));
+    let since_epoch=now-self.epoch;
+    let micros=since_epoch.as_secs()*1_000_000+since_epoch.subsec_micros() as u64;
+    let mut data=Vec::with_capacity(12);
+    network::encode_into(&mut data,&(seq,micros)).unwrap();
+    data
+  }
+  
+  ///Match the payload of a `'repl'` received at `now` to its ping, recording its round-trip time.
+  ///Returns `None` if the reply does not match any pending ping.
+  pub fn on_reply(&mut self,data: &[u8],now: Instant)->Option<Duration> {
+    let (seq,_micros): (u32,u64)=network::decode_from(data).ok()?;
+    let idx=self.pending.iter().position(|&(pending,_)| pending==seq)?;
+    let sent=self.pending[idx].1;
+    //Older pings are either lost or reordered, either way they are no longer useful
+    self.pending.drain(..=idx);
+    let rtt=now-sent;
+    if self.rtts.len()>=self.window {
+      self.rtts.pop_front();
+    }
+    self.rtts.push_back(secs_from_duration(rtt) as f32);
+    Some(rtt)
+  }
+  
+  ///When the oldest ping still waiting for a reply was sent.
+  pub fn oldest_pending(&self)->Option<Instant> {
+    self.pending.front().map(|&(_,sent)| sent)
+  }
+  
+  ///Forget about pending pings, for example after reconnecting.
+  ///Statistics are kept.
+  pub fn clear_pending(&mut self) {
+    self.pending.clear();
+  }
+  
+  ///Summarize the round-trip times in the window, if there are any.
+  pub fn summary(&self)->Option<LatencySummary> {
+    if self.rtts.is_empty() {
+      return None;
+    }
+    let mut sorted: Vec<f32>=self.rtts.iter().cloned().collect();
+    sorted.sort_by(|a,b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+    let percentile=|p: f32| sorted[((sorted.len()-1) as f32*p).round() as usize];
+    //Jitter as the mean difference between consecutive round-trip times
+    let jitter=if self.rtts.len()>1 {
+      let diffs: f32=self.rtts.iter().zip(self.rtts.iter().skip(1)).map(|(a,b)| (b-a).abs()).sum();
+      diffs/(self.rtts.len()-1) as f32
+    }else{
+      0.0
+    };
+    Some(LatencySummary{
+      samples: sorted.len(),
+      min: sorted[0],
+      median: percentile(0.5),
+      p95: percentile(0.95),
+      jitter,
+    })
+  }
+}
+
+///Round-trip time statistics, in seconds.
+#[derive(Copy,Clone,Debug)]
+pub struct LatencySummary {
+  ///How many round-trips the statistics are based on.
+  pub samples: usize,
+  pub min: f32,
+  pub median: f32,
+  ///95th percentile.
+  pub p95: f32,
+  ///Mean absolute difference between consecutive round-trip times.
+  pub jitter: f32,
+}
+impl fmt::Display for LatencySummary {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    write!(
+      f,"round-trip min {:.1}ms, median {:.1}ms, p95 {:.1}ms, jitter {:.1}ms ({} samples)",
+      self.min*1000.0,self.median*1000.0,self.p95*1000.0,self.jitter*1000.0,self.samples
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  fn ms(ms: u64)->Duration {Duration::from_millis(ms)}
+  
+  ///A tracker that got replies with the given round-trip times, in milliseconds.
+  fn tracker(window: usize,rtts: &[u64])->PingTracker {
+    let mut pings=PingTracker::new(window);
+    let mut now=pings.epoch;
+    for &rtt in rtts {
+      let ping=pings.make_ping(now);
+      assert_eq!(pings.on_reply(&ping,now+ms(rtt)),Some(ms(rtt)));
+      now+=ms(1000);
+    }
+    pings
+  }
+  
+  #[test]
+  fn reply_matching() {
+    let mut pings=PingTracker::new(8);
+    let t0=pings.epoch;
+    let sent: Vec<Vec<u8>>=(0..4).map(|i| pings.make_ping(t0+ms(i*10))).collect();
+    //Replies out of order drop the pings sent before them
+    assert_eq!(pings.on_reply(&sent[1],t0+ms(50)),Some(ms(40)));
+    assert_eq!(pings.on_reply(&sent[0],t0+ms(60)),None);
+    //Unknown ids and malformed payloads
+    let mut unknown=sent[3].clone();
+    unknown[3]=99;
+    assert_eq!(pings.on_reply(&unknown,t0+ms(70)),None);
+    assert_eq!(pings.on_reply(&sent[3][..6],t0+ms(70)),None);
+    assert_eq!(pings.on_reply(&sent[3],t0+ms(80)),Some(ms(50)));
+    //Ping 2 went with ping 3
+    assert_eq!(pings.on_reply(&sent[2],t0+ms(90)),None);
+    assert_eq!(pings.summary().unwrap().samples,2);
+  }
+  
+  #[test]
+  fn pending_pings() {
+    let mut pings=PingTracker::new(8);
+    let t0=pings.epoch;
+    assert_eq!(pings.oldest_pending(),None);
+    let first=pings.make_ping(t0);
+    pings.make_ping(t0+ms(100));
+    let third=pings.make_ping(t0+ms(200));
+    //The oldest unanswered ping is what times out
+    assert_eq!(pings.oldest_pending(),Some(t0));
+    pings.on_reply(&first,t0+ms(250));
+    assert_eq!(pings.oldest_pending(),Some(t0+ms(100)));
+    pings.clear_pending();
+    assert_eq!(pings.oldest_pending(),None);
+    assert_eq!(pings.on_reply(&third,t0+ms(300)),None);
+    assert_eq!(pings.summary().unwrap().samples,1);
+  }
+  
+  #[test]
+  fn latency_summary() {
+    let ramp: Vec<u64>=(1..=20).collect();
+    //(window,rtts,(samples,min,median,p95,jitter)), in milliseconds
+    type Stats=(usize,f32,f32,f32,f32);
+    let table: Vec<(usize,&[u64],Stats)>=vec![
+      (8,&[10],(1,10.0,10.0,10.0,0.0)),
+      (8,&[10,30,20],(3,10.0,20.0,30.0,15.0)),
+      (32,&ramp,(20,1.0,11.0,19.0,1.0)),
+      //Only the window counts
+      (4,&[100,100,1,2,3,4],(4,1.0,3.0,4.0,1.0)),
+      (8,&[10,50,10,50],(4,10.0,50.0,50.0,40.0)),
+    ];
+    for &(window,rtts,(samples,min,median,p95,jitter)) in table.iter() {
+      let summary=tracker(window,rtts).summary().unwrap();
+      let close=|a: f32,b: f32| (a*1000.0-b).abs()<1e-3;
+      assert_eq!(summary.samples,samples,"{:?}",rtts);
+      assert!(close(summary.min,min),"{:?}: {}",rtts,summary);
+      assert!(close(summary.median,median),"{:?}: {}",rtts,summary);
+      assert!(close(summary.p95,p95),"{:?}: {}",rtts,summary);
+      assert!(close(summary.jitter,jitter),"{:?}: {}",rtts,summary);
+    }
+    assert!(PingTracker::new(8).summary().is_none());
+  }
+}
@@ -0,0 +1,506 @@
+use prelude::*;
+use std::collections::{BTreeMap};
+use serde::de::{DeserializeOwned};
+use network;
+use rect::{Pair};
+use {MouseMove,KeyPress,PenEvent,PenTool};
+use absm::touch::{Contact,Phase,Sample};
+use absm::capability::{Capability};
+use absm::compact::{CompactTouch,CompactSample,CompactDelta};
+
+///A malformed packet, received from a possibly buggy remote.
+///Decode errors only affect the offending packet and should not end the session.
+#[derive(Debug,Clone,PartialEq)]
+pub enum DecodeError {
+  ///The packet is shorter than the 4-byte type header.
+  MissingType,
+  ///The packet ended before the given field was complete.
+  Truncated{field: &'static str},
+  ///A header field value could not be decoded as the expected type.
+  BadHeader{key: String},
+  ///A field holds a value outside of its allowed range.
+  Invalid{field: &'static str},
+  ///The packet is larger than the configured maximum packet size and was dropped.
+  TooLarge{len: usize,max: usize},
+}
+impl fmt::Display for DecodeError {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    match self {
+      DecodeError::MissingType=>write!(f,"packet too short to hold a type"),
+      DecodeError::Truncated{field}=>write!(f,"packet ends in the middle of the {} field",field),
+      DecodeError::BadHeader{key}=>write!(f,"malformed value for header '{}'",key),
+      DecodeError::Invalid{field}=>write!(f,"invalid value in the {} field",field),
+      DecodeError::TooLarge{len,max}=>write!(
+        f,"packet of {} bytes exceeds the maximum packet size of {} bytes",len,max
+      ),
+    }
+  }
+}
+impl ErrorTrait for DecodeError {}
+
+pub type DecodeResult<T> = ::std::result::Result<T,DecodeError>;
+
+///The header fields of an `'absM'`, `'sInf'` or `'setp'` packet.
+///Keys are unique, a repeated key overrides the previous value.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Headers(BTreeMap<Vec<u8>,Vec<u8>>);
+impl Headers {
+  pub fn new()->Headers {Headers::default()}
+  
+  ///Set a header to a network-encoded value.
+  pub fn insert<T: Serialize>(&mut self,key: &str,val: &T) {
+    let mut raw=Vec::new();
+    network::encode_into(&mut raw,val).unwrap();
+    self.insert_raw(key,raw);
+  }
+  ///Set a header to a raw byte string.
+  pub fn insert_raw<V: Into<Vec<u8>>>(&mut self,key: &str,val: V) {
+    self.0.insert(key.as_bytes().to_vec(),val.into());
+  }
+  
+  ///Get the network-decoded value of a header, if present.
+  ///Extra bytes after the value are ignored.
+  pub fn get<T: DeserializeOwned>(&self,key: &str)->DecodeResult<Option<T>> {
+    match self.get_raw(key) {
+      Some(raw)=>network::decode_from(raw)
+        .map(Some)
+        .map_err(|_| DecodeError::BadHeader{key: key.to_string()}),
+      None=>Ok(None),
+    }
+  }
+  ///Get the raw bytes of a header, if present.
+  pub fn get_raw(&self,key: &str)->Option<&[u8]> {
+    self.0.get(key.as_bytes()).map(|val| &val[..])
+  }
+  
+  ///Iterate over all raw key/value pairs.
+  pub fn iter<'a>(&'a self)->impl Iterator<Item=(&'a [u8],&'a [u8])>+'a {
+    self.0.iter().map(|(key,val)| (&key[..],&val[..]))
+  }
+  
+  fn encode(&self,buf: &mut Vec<u8>) {
+    for (key,val) in self.iter() {
+      encode_string(buf,key);
+      encode_string(buf,val);
+    }
+  }
+  fn decode(mut buf: &[u8])->DecodeResult<Headers> {
+    let mut headers=Headers::new();
+    while !buf.is_empty() {
+      let key=take_string(&mut buf,"header key")?;
+      let val=take_string(&mut buf,"header value")?;
+      headers.0.insert(key.to_vec(),val.to_vec());
+    }
+    Ok(headers)
+  }
+}
+
+///A single `absM` packet.
+#[derive(Debug)]
+pub enum Packet {
+  ///`'absM'`, sent by the client to open a connection.
+  HandshakeOpen{version: (u16,u16),headers: Headers},
+  ///`'sInf'`, sent by the server on handshake and whenever its information changes.
+  ServerInfo{version: (u16,u16),headers: Headers},
+  ///`'setp'`, sent by the client to finish the handshake.
+  Setup{headers: Headers},
+  ///`'mmov'`, or its alias `'tuch'`.
+  Touch(MouseMove),
+  ///`'keyp'`.
+  Key(KeyPress),
+  ///`'mtch'`, any number of touch contacts.
+  MultiTouch(Vec<Contact>),
+  ///`'pen '`, a stylus event.
+  Pen(PenEvent),
+  ///`'btch'`, timestamped touch samples in chronological order.
+  Batch(Vec<Sample>),
+  ///`'cmov'` or `'dmov'`, a touch sample in fixed point.
+  Compact(CompactTouch),
+  ///`'bye '`, closing the connection for the given reason.
+  Bye(String),
+  ///`'kpal'`, sent when nothing else was sent for a keepalive interval.
+  KeepAlive,
+  ///`'rdnt'`, the newest touch sample along with repeats of the samples before it.
+  ///`seq` is the sequence number of the last sample, which numbers samples consecutively.
+  Redundant{seq: u32,samples: Vec<Sample>},
+  ///`'seqn'`, an event packet tagged with a sequence number.
+  Sequenced{seq: u32,packet: Box<Packet>},
+  ///`'ping'`, with arbitrary data to be echoed back.
+  Ping(Vec<u8>),
+  ///`'repl'`, echoing the data of a ping.
+  Reply(Vec<u8>),
+  ///Any packet type not known to this version of the protocol.
+  Unknown{ty: [u8; 4],data: Vec<u8>},
+}
+impl Packet {
+  ///The 4-byte type of this packet.
+  pub fn ty(&self)->[u8; 4] {
+    match self {
+      Packet::HandshakeOpen{..}=>*b"absM",
+      Packet::ServerInfo{..}=>*b"sInf",
+      Packet::Setup{..}=>*b"setp",
+      Packet::Touch(..)=>*b"mmov",
+      Packet::Key(..)=>*b"keyp",
+      Packet::MultiTouch(..)=>*b"mtch",
+      Packet::Pen(..)=>*b"pen ",
+      Packet::Batch(..)=>*b"btch",
+      Packet::Compact(CompactTouch::Absolute{..})=>*b"cmov",
+      Packet::Compact(CompactTouch::Delta{..})=>*b"dmov",
+      Packet::Redundant{..}=>*b"rdnt",
+      Packet::Sequenced{..}=>*b"seqn",
+      Packet::Bye(..)=>*b"bye ",
+      Packet::KeepAlive=>*b"kpal",
+      Packet::Ping(..)=>*b"ping",
+      Packet::Reply(..)=>*b"repl",
+      Packet::Unknown{ty,..}=>*ty,
+    }
+  }
+  
+  ///The capability both sides must agree on before this packet can be sent, if any.
+  pub fn capability(&self)->Option<Capability> {
+    match self {
+      Packet::MultiTouch(..)=>Some(Capability::MultiTouch),
+      Packet::Pen(..)=>Some(Capability::Pen),
+      Packet::Batch(..)=>Some(Capability::Batch),
+      Packet::Compact(..)=>Some(Capability::Compact),
+      Packet::Sequenced{..}=>Some(Capability::Sequence),
+      _ => None,
+    }
+  }
+  
+  ///Whether this packet only carries positions, so that a newer one supersedes it entirely.
+  ///Pen packets carry the whole pen state, which a newer pen packet replaces as well.
+  pub fn is_position(&self)->bool {
+    matches!(self,Packet::Touch(..) | Packet::Compact(..) | Packet::Batch(..) | Packet::Pen(..))
+  }
+  
+  ///Append the encoded packet to `buf`.
+  pub fn encode(&self,buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&self.ty());
+    match self {
+      Packet::HandshakeOpen{version,headers} | Packet::ServerInfo{version,headers}=>{
+        network::encode_into(&mut *buf,version).unwrap();
+        headers.encode(buf);
+      },
+      Packet::Setup{headers}=>headers.encode(buf),
+      Packet::Touch(ev)=>network::encode_into(&mut *buf,ev).unwrap(),
+      Packet::Key(ev)=>{
+        encode_string(buf,ev.key.as_bytes());
+        encode_string(buf,ev.scancode.as_bytes());
+        network::encode_into(&mut *buf,&(ev.down as u8,ev.modifiers)).unwrap();
+      },
+      Packet::MultiTouch(contacts)=>{
+        network::encode_into(&mut *buf,&(contacts.len() as u16)).unwrap();
+        for contact in contacts {
+          network::encode_into(&mut *buf,&(contact.id,contact.phase.as_u8(),&contact.ev)).unwrap();
+        }
+      },
+      Packet::Pen(pen)=>{
+        let tool=match pen.tool {
+          PenTool::Pen=>0u8,
+          PenTool::Eraser=>1,
+        };
+        let flags=pen.touching as u8 | pen.buttons<<1;
+        network::encode_into(&mut *buf,&(&pen.ev,pen.hover,pen.tilt,pen.orientation,tool,flags)).unwrap();
+      },
+      Packet::Batch(samples)=>{
+        network::encode_into(&mut *buf,&(samples.len() as u16)).unwrap();
+        for sample in samples {
+          network::encode_into(&mut *buf,&(sample.time,&sample.ev)).unwrap();
+        }
+      },
+      Packet::Compact(CompactTouch::Absolute{num,sample})=>{
+        let CompactSample{pos,pressure,size}=*sample;
+        network::encode_into(&mut *buf,&(num,pos[0],pos[1],pressure,size)).unwrap();
+      },
+      Packet::Compact(CompactTouch::Delta{num,delta})=>{
+        let CompactDelta{pos,pressure,size}=*delta;
+        network::encode_into(&mut *buf,&(num,pos[0],pos[1],pressure,size)).unwrap();
+      },
+      Packet::Redundant{seq,samples}=>{
+        network::encode_into(&mut *buf,&(seq,samples.len() as u8)).unwrap();
+        for sample in samples {
+          network::encode_into(&mut *buf,&(sample.time,&sample.ev)).unwrap();
+        }
+      },
+      Packet::Sequenced{seq,packet}=>{
+        network::encode_into(&mut *buf,seq).unwrap();
+        packet.encode(buf);
+      },
+      Packet::Bye(reason)=>buf.extend_from_slice(reason.as_bytes()),
+      Packet::KeepAlive=>{},
+      Packet::Ping(data) | Packet::Reply(data) | Packet::Unknown{data,..}=>{
+        buf.extend_from_slice(data);
+      },
+    }
+  }
+  
+  ///Decode a whole packet.
+  pub fn decode(buf: &[u8])->DecodeResult<Packet> {
+    if buf.len()<4 {
+      return Err(DecodeError::MissingType);
+    }
+    let mut ty=[0; 4];
+    ty.copy_from_slice(&buf[..4]);
+    let mut data=&buf[4..];
+    Ok(match &ty {
+      b"absM"=>{
+        let version=take_decode(&mut data,4,"version")?;
+        Packet::HandshakeOpen{version,headers: Headers::decode(data)?}
+      },
+      b"sInf"=>{
+        let version=take_decode(&mut data,4,"version")?;
+        Packet::ServerInfo{version,headers: Headers::decode(data)?}
+      },
+      b"setp"=>Packet::Setup{headers: Headers::decode(data)?},
+      b"mmov" | b"tuch"=>Packet::Touch(take_decode(&mut data,16,"touch")?),
+      b"keyp"=>{
+        let key=String::from_utf8_lossy(take_string(&mut data,"key name")?).into_owned();
+        let scancode=String::from_utf8_lossy(take_string(&mut data,"scancode")?).into_owned();
+        let (down,modifiers): (u8,u16)=take_decode(&mut data,3,"key state")?;
+        Packet::Key(KeyPress{key,scancode,down: down!=0,modifiers})
+      },
+      b"mtch"=>{
+        let count: u16=take_decode(&mut data,2,"contact count")?;
+        let mut contacts=Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          let (id,phase): (u32,u8)=take_decode(&mut data,5,"contact")?;
+          let phase=Phase::from_u8(phase).ok_or(DecodeError::Invalid{field: "contact phase"})?;
+          let ev=take_decode(&mut data,16,"contact")?;
+          contacts.push(Contact{id,phase,ev});
+        }
+        Packet::MultiTouch(contacts)
+      },
+      b"pen "=>{
+        let (ev,hover,tilt,orientation,tool,flags): (MouseMove,f32,Pair<f32>,f32,u8,u8)=
+          take_decode(&mut data,34,"pen")?;
+        let tool=match tool {
+          0=>PenTool::Pen,
+          1=>PenTool::Eraser,
+          _ => return Err(DecodeError::Invalid{field: "pen tool"}),
+        };
+        Packet::Pen(PenEvent{ev,hover,tilt,orientation,tool,touching: flags&0x1!=0,buttons: flags>>1})
+      },
+      b"btch"=>{
+        let count: u16=take_decode(&mut data,2,"sample count")?;
+        let mut samples=Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          let (time,ev)=take_decode(&mut data,24,"sample")?;
+          samples.push(Sample{time,ev});
+        }
+        Packet::Batch(samples)
+      },
+      b"cmov"=>{
+        let (num,x,y,pressure,size)=take_decode(&mut data,7,"compact touch")?;
+        Packet::Compact(CompactTouch::Absolute{num,sample: CompactSample{pos: [x,y],pressure,size}})
+      },
+      b"dmov"=>{
+        let (num,x,y,pressure,size)=take_decode(&mut data,5,"compact delta")?;
+        Packet::Compact(CompactTouch::Delta{num,delta: CompactDelta{pos: [x,y],pressure,size}})
+      },
+      b"rdnt"=>{
+        let (seq,count): (u32,u8)=take_decode(&mut data,5,"sample sequence")?;
+        let mut samples=Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          let (time,ev)=take_decode(&mut data,24,"sample")?;
+          samples.push(Sample{time,ev});
+        }
+        Packet::Redundant{seq,samples}
+      },
+      b"seqn"=>{
+        let seq=take_decode(&mut data,4,"sequence number")?;
+        let packet=Packet::decode(data)?;
+        if let Packet::Sequenced{..}=packet {
+          return Err(DecodeError::Invalid{field: "sequenced packet"});
+        }
+        Packet::Sequenced{seq,packet: Box::new(packet)}
+      },
+      b"bye "=>Packet::Bye(String::from_utf8_lossy(data).into_owned()),
+      b"kpal"=>Packet::KeepAlive,
+      b"ping"=>Packet::Ping(data.to_vec()),
+      b"repl"=>Packet::Reply(data.to_vec()),
+      _ => Packet::Unknown{ty,data: data.to_vec()},
+    })
+  }
+}
+
+fn encode_string(buf: &mut Vec<u8>,string: &[u8]) {
+  network::encode_into(&mut *buf,&(string.len() as u32)).unwrap();
+  buf.extend_from_slice(string);
+}
+
+///Split `len` bytes off the front of `buf`.
+fn take<'a>(buf: &mut &'a [u8],len: usize,field: &'static str)->DecodeResult<&'a [u8]> {
+  if buf.len()<len {
+    return Err(DecodeError::Truncated{field});
+  }
+  let (head,tail)=buf.split_at(len);
+  *buf=tail;
+  Ok(head)
+}
+
+///Split `len` bytes off the front of `buf` and network-decode them.
+fn take_decode<T: DeserializeOwned>(buf: &mut &[u8],len: usize,field: &'static str)->DecodeResult<T> {
+  network::decode_from(take(buf,len,field)?).map_err(|_| DecodeError::Truncated{field})
+}
+
+///Split a 4-byte-length-prefixed string off the front of `buf`.
+fn take_string<'a>(buf: &mut &'a [u8],field: &'static str)->DecodeResult<&'a [u8]> {
+  let len: u32=take_decode(buf,4,field)?;
+  take(buf,len as usize,field)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  fn ev(x: f32)->MouseMove {
+    MouseMove{pos: Pair([x,x*2.0]),pressure: 0.5,size: 0.25}
+  }
+  fn sample(time: u64)->Sample {
+    Sample{time,ev: ev(time as f32)}
+  }
+  fn pen(tool: PenTool)->PenEvent {
+    PenEvent{
+      ev: ev(3.0),hover: 0.0,tilt: Pair([0.1,-0.2]),orientation: 1.5,tool,touching: true,buttons: 0b10,
+    }
+  }
+  fn headers()->Headers {
+    let mut headers=Headers::new();
+    headers.insert("frame_delay",&0.25f32);
+    headers.insert_raw("client_name","test");
+    headers.insert_raw("empty","");
+    headers
+  }
+  fn encode(packet: &Packet)->Vec<u8> {
+    let mut buf=Vec::new();
+    packet.encode(&mut buf);
+    buf
+  }
+  
+  ///One packet of every variant.
+  fn every_packet()->Vec<Packet> {
+    vec![
+      Packet::HandshakeOpen{version: (1,11),headers: headers()},
+      Packet::ServerInfo{version: (1,3),headers: headers()},
+      Packet::Setup{headers: Headers::new()},
+      Packet::Touch(ev(1.0)),
+      Packet::Key(KeyPress{key: "a".into(),scancode: "a".into(),down: true,modifiers: 0x41}),
+      Packet::MultiTouch(vec![
+        Contact{id: 7,phase: Phase::Down,ev: ev(1.0)},
+        Contact{id: 9,phase: Phase::Cancel,ev: ev(2.0)},
+      ]),
+      Packet::Pen(pen(PenTool::Pen)),
+      Packet::Pen(pen(PenTool::Eraser)),
+      Packet::Batch(vec![sample(10),sample(20)]),
+      Packet::Compact(CompactTouch::Absolute{
+        num: 3,sample: CompactSample{pos: [1,0xffff],pressure: 2,size: 3},
+      }),
+      Packet::Compact(CompactTouch::Delta{
+        num: 4,delta: CompactDelta{pos: [-1,1],pressure: -128,size: 127},
+      }),
+      Packet::Redundant{seq: 0xffff_ffff,samples: vec![sample(1),sample(2),sample(3)]},
+      Packet::Sequenced{seq: 5,packet: Box::new(Packet::Batch(vec![sample(30)]))},
+      Packet::Bye("done".into()),
+      Packet::KeepAlive,
+      Packet::Ping(vec![1,2,3]),
+      Packet::Reply(vec![]),
+      Packet::Unknown{ty: *b"zzzz",data: vec![9,8]},
+    ]
+  }
+  
+  #[test]
+  fn packet_round_trip() {
+    for packet in every_packet() {
+      let buf=encode(&packet);
+      let decoded=Packet::decode(&buf).unwrap_or_else(|err| panic!("{:?} failed: {}",packet,err));
+      assert_eq!(decoded.ty(),packet.ty());
+      assert_eq!(encode(&decoded),buf,"{:?} decoded as {:?}",packet,decoded);
+    }
+    //'tuch' is an alias of 'mmov'
+    let mut buf=encode(&Packet::Touch(ev(1.0)));
+    buf[..4].copy_from_slice(b"tuch");
+    assert!(matches!(Packet::decode(&buf),Ok(Packet::Touch(..))));
+  }
+  
+  #[test]
+  fn headers_round_trip() {
+    let headers=headers();
+    match Packet::decode(&encode(&Packet::Setup{headers: headers.clone()})) {
+      Ok(Packet::Setup{headers: decoded})=>assert_eq!(decoded,headers),
+      other=>panic!("headers decoded as {:?}",other),
+    }
+    assert_eq!(headers.get::<f32>("frame_delay"),Ok(Some(0.25)));
+    assert_eq!(headers.get_raw("client_name"),Some(&b"test"[..]));
+    assert_eq!(headers.get::<f32>("missing"),Ok(None));
+    assert_eq!(headers.get::<f32>("empty"),Err(DecodeError::BadHeader{key: "empty".into()}));
+  }
+  
+  #[test]
+  fn truncated_packets() {
+    const COMPACT_SAMPLE: CompactSample=CompactSample{pos: [0,0],pressure: 0,size: 0};
+    const COMPACT_DELTA: CompactDelta=CompactDelta{pos: [0,0],pressure: 0,size: 0};
+    let table: Vec<(Packet,&str)>=vec![
+      (Packet::HandshakeOpen{version: (1,11),headers: headers()},"header value"),
+      (Packet::Touch(ev(1.0)),"touch"),
+      (Packet::Key(KeyPress{key: "a".into(),scancode: "b".into(),down: false,modifiers: 0}),"key state"),
+      (Packet::MultiTouch(vec![Contact{id: 1,phase: Phase::Move,ev: ev(1.0)}]),"contact"),
+      (Packet::Pen(pen(PenTool::Pen)),"pen"),
+      (Packet::Batch(vec![sample(1)]),"sample"),
+      (Packet::Compact(CompactTouch::Absolute{num: 0,sample: COMPACT_SAMPLE}),"compact touch"),
+      (Packet::Compact(CompactTouch::Delta{num: 0,delta: COMPACT_DELTA}),"compact delta"),
+      (Packet::Redundant{seq: 1,samples: vec![sample(1)]},"sample"),
+      (Packet::Sequenced{seq: 1,packet: Box::new(Packet::Touch(ev(1.0)))},"touch"),
+    ];
+    for (packet,field) in table {
+      let buf=encode(&packet);
+      assert_eq!(Packet::decode(&buf[..buf.len()-1]).err(),Some(DecodeError::Truncated{field}),"{:?}",packet);
+    }
+    //Truncated lengths, counts and sequence numbers
+    let table: &[(&[u8],&str)]=&[
+      (b"absM\x00\x01","version"),
+      (b"setp\x00\x00","header key"),
+      (b"setp\x00\x00\x00\x01k\x00\x00","header value"),
+      (b"setp\x00\x00\x00\x05key","header key"),
+      (b"mtch\x00","contact count"),
+      (b"mtch\x00\x01\x00\x00\x00\x01","contact"),
+      (b"btch\x00\x02","sample"),
+      (b"rdnt\x00\x00\x00\x01","sample sequence"),
+      (b"seqn\x00\x00","sequence number"),
+    ];
+    for &(buf,field) in table.iter() {
+      assert_eq!(Packet::decode(buf).err(),Some(DecodeError::Truncated{field}),"{:?}",buf);
+    }
+    assert_eq!(Packet::decode(b"mmo").err(),Some(DecodeError::MissingType));
+    assert_eq!(Packet::decode(b"seqn\x00\x00\x00\x01mm").err(),Some(DecodeError::MissingType));
+  }
+  
+  #[test]
+  fn unknown_packet_type() {
+    match Packet::decode(b"abcdxyz") {
+      Ok(Packet::Unknown{ty,data})=>{
+        assert_eq!(&ty,b"abcd");
+        assert_eq!(data,b"xyz");
+      },
+      other=>panic!("unknown packet decoded as {:?}",other),
+    }
+    //Unknown packets may still be sequenced
+    assert!(matches!(Packet::decode(b"seqn\x00\x00\x00\x01abcd"),Ok(Packet::Sequenced{..})));
+  }
+  
+  #[test]
+  fn invalid_fields() {
+    //Tool byte right after the pen event, hover, tilt and orientation
+    let mut buf=encode(&Packet::Pen(pen(PenTool::Pen)));
+    buf[4+32]=2;
+    assert_eq!(Packet::decode(&buf).err(),Some(DecodeError::Invalid{field: "pen tool"}));
+    //Phase byte right after the contact count and id
+    let mut buf=encode(&Packet::MultiTouch(vec![Contact{id: 1,phase: Phase::Up,ev: ev(1.0)}]));
+    buf[4+2+4]=0xff;
+    assert_eq!(Packet::decode(&buf).err(),Some(DecodeError::Invalid{field: "contact phase"}));
+    //Sequence numbers don't nest
+    let inner=Packet::Sequenced{seq: 2,packet: Box::new(Packet::KeepAlive)};
+    let nested=Packet::Sequenced{seq: 1,packet: Box::new(inner)};
+    assert_eq!(Packet::decode(&encode(&nested)).err(),Some(DecodeError::Invalid{field: "sequenced packet"}));
+  }
+}
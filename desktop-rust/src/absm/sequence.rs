@@ -0,0 +1,238 @@
+use prelude::*;
+
+///How a sequenced packet relates to the ones received before it.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Arrival {
+  ///Newer than any packet received so far.
+  Fresh,
+  ///Older than the newest packet, but not received before.
+  Late,
+  ///Already received.
+  Duplicate,
+}
+
+///Keeps track of the sequence numbers of `'seqn'` packets to spot lost, late and duplicated
+///datagrams.
+#[derive(Default)]
+pub struct SequenceTracker {
+  ///Newest sequence number received.
+  highest: Option<u32>,
+  ///Bit `i` is set if packet `highest-i` was received.
+  window: u64,
+  received: u64,
+  lost: u64,
+  late: u64,
+  duplicates: u64,
+}
+impl SequenceTracker {
+  pub fn new()->SequenceTracker {SequenceTracker::default()}
+  
+  ///Forget all sequence numbers and statistics, for example because the remote restarted its
+  ///sequence.
+  pub fn reset(&mut self) {
+    *self=SequenceTracker::default();
+  }
+  
+  ///Record the arrival of a packet with sequence number `seq`.
+  ///Sequence numbers wrap around, so "newer" means less than half the sequence space ahead.
+  pub fn on_packet(&mut self,seq: u32)->Arrival {
+    let highest=match self.highest {
+      Some(highest)=>highest,
+      None=>{
+        self.highest=Some(seq);
+        self.window=1;
+        self.received+=1;
+        return Arrival::Fresh;
+      },
+    };
+    let ahead=seq.wrapping_sub(highest) as i32;
+    if ahead>0 {
+      //Every packet skipped over is lost, until it shows up late
+      let ahead=ahead as u64;
+      self.lost+=ahead-1;
+      self.window=if ahead>=64 {0}else{self.window<<ahead};
+      self.window|=1;
+      self.highest=Some(seq);
+      self.received+=1;
+      Arrival::Fresh
+    }else{
+      let behind=-(ahead as i64) as u64;
+      if behind>=64 {
+        //Too old to tell apart from a duplicate, and already counted as lost
+        self.late+=1;
+        return Arrival::Late;
+      }
+      let bit=1<<behind;
+      if self.window&bit!=0 {
+        self.duplicates+=1;
+        Arrival::Duplicate
+      }else{
+        self.window|=bit;
+        self.lost=self.lost.saturating_sub(1);
+        self.late+=1;
+        self.received+=1;
+        Arrival::Late
+      }
+    }
+  }
+  
+  ///Summarize losses so far, if any sequenced packet was received.
+  pub fn summary(&self)->Option<LossSummary> {
+    self.highest.map(|_| LossSummary{
+      received: self.received,
+      lost: self.lost,
+      late: self.late,
+      duplicates: self.duplicates,
+    })
+  }
+}
+
+///Packet loss statistics for a sequenced connection.
+#[derive(Copy,Clone,Debug)]
+pub struct LossSummary {
+  pub received: u64,
+  ///Packets never received.
+  pub lost: u64,
+  ///Packets received after a newer one.
+  pub late: u64,
+  ///Packets received more than once.
+  pub duplicates: u64,
+}
+impl LossSummary {
+  ///Fraction of packets sent that were never received.
+  pub fn loss_rate(&self)->f32 {
+    let sent=self.received+self.lost;
+    if sent==0 {0.0}else{self.lost as f32/sent as f32}
+  }
+}
+impl fmt::Display for LossSummary {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    write!(
+      f,"packet loss {:.1}% ({} lost, {} received, {} late, {} duplicated)",
+      self.loss_rate()*100.0,self.lost,self.received,self.late,self.duplicates
+    )
+  }
+}
+
+///Rebuilds the sample stream from `'rdnt'` packets, which repeat earlier samples in case the
+///packets carrying them were lost.
+#[derive(Default)]
+pub struct SampleDedup {
+  ///Sequence number of the newest sample applied.
+  newest: Option<u32>,
+  ///Samples that were only received as repeats, after losing their original packet.
+  recovered: u64,
+  ///Samples lost along with every packet repeating them.
+  missed: u64,
+}
+impl SampleDedup {
+  pub fn new()->SampleDedup {SampleDedup::default()}
+  
+  ///Forget all samples and statistics, for example because the remote restarted its sequence.
+  pub fn reset(&mut self) {
+    *self=SampleDedup::default();
+  }
+  
+  ///Given a packet of `count` consecutive samples, the newest with sequence number `last`,
+  ///return how many of the newest samples have not been applied yet.
+  pub fn fresh(&mut self,last: u32,count: usize)->usize {
+    let count=count as u64;
+    let ahead=match self.newest {
+      Some(newest)=>last.wrapping_sub(newest) as i32,
+      None=>{
+        self.newest=Some(last);
+        return count as usize;
+      },
+    };
+    if ahead<=0 || count==0 {
+      return 0;
+    }
+    let ahead=ahead as u64;
+    let fresh=ahead.min(count);
+    self.missed+=ahead-fresh;
+    self.recovered+=fresh-1;
+    self.newest=Some(last);
+    fresh as usize
+  }
+  
+  ///Samples recovered from repeats and samples missed altogether, if any sample was received.
+  pub fn summary(&self)->Option<(u64,u64)> {
+    self.newest.map(|_| (self.recovered,self.missed))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  #[test]
+  fn sequence_arrivals() {
+    use self::Arrival::*;
+    //Sequence numbers wrap around in the middle of the stream
+    let table: &[(u32,Arrival)]=&[
+      (0xffff_fffd,Fresh),
+      (0xffff_fffe,Fresh),
+      (1,Fresh),
+      (0xffff_ffff,Late),
+      (0xffff_ffff,Duplicate),
+      (1,Duplicate),
+      (5,Fresh),
+      (3,Late),
+      (0,Late),
+      (0,Duplicate),
+      (6,Fresh),
+      //Too old to tell apart
+      (0x8000_0006,Late),
+      (5u32.wrapping_sub(100),Late),
+    ];
+    let mut tracker=SequenceTracker::new();
+    assert!(tracker.summary().is_none());
+    for &(seq,arrival) in table {
+      assert_eq!(tracker.on_packet(seq),arrival,"sequence number {:#x}",seq);
+    }
+    let summary=tracker.summary().unwrap();
+    //Only 2 and 4 never showed up
+    assert_eq!((summary.received,summary.lost,summary.late,summary.duplicates),(8,2,5,3));
+    assert!((summary.loss_rate()-0.2).abs()<1e-6);
+    tracker.reset();
+    assert!(tracker.summary().is_none());
+    assert_eq!(tracker.on_packet(3),Fresh);
+  }
+  
+  #[test]
+  fn far_jump_clears_window() {
+    let mut tracker=SequenceTracker::new();
+    tracker.on_packet(10);
+    assert_eq!(tracker.on_packet(10+64),Arrival::Fresh);
+    //Packet 10 is out of the window now
+    assert_eq!(tracker.on_packet(10),Arrival::Late);
+    assert_eq!(tracker.on_packet(11+64),Arrival::Fresh);
+    assert_eq!(tracker.on_packet(11+63),Arrival::Duplicate);
+    assert_eq!(tracker.summary().unwrap().lost,63);
+  }
+  
+  #[test]
+  fn redundant_samples() {
+    //Each row is the sequence number of the newest sample, how many samples the packet
+    //carries, and how many of them are new
+    let table: &[(u32,usize,usize)]=&[
+      (0xffff_fffe,3,3),
+      //Wraps around, repeating 0xffff_fffe and recovering 0xffff_ffff and 0
+      (1,4,3),
+      (1,4,0),
+      //Samples 2 to 6 are lost along with every packet repeating them
+      (10,4,4),
+      (5,4,0),
+      (11,1,1),
+      (12,0,0),
+    ];
+    let mut dedup=SampleDedup::new();
+    assert!(dedup.summary().is_none());
+    for &(last,count,fresh) in table {
+      assert_eq!(dedup.fresh(last,count),fresh,"packet ending at sample {:#x}",last);
+    }
+    assert_eq!(dedup.summary(),Some((5,5)));
+    dedup.reset();
+    assert!(dedup.summary().is_none());
+  }
+}
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap};
+use MouseMove;
+
+///The lifecycle phase of a touch contact.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Phase {
+  ///The pointer touched the screen.
+  Down,
+  ///The pointer moved while touching the screen.
+  Move,
+  ///The pointer left the screen.
+  Up,
+  ///The device gave up tracking the pointer, for example because of palm rejection.
+  Cancel,
+}
+impl Phase {
+  pub fn from_u8(phase: u8)->Option<Phase> {
+    Some(match phase {
+      0=>Phase::Down,
+      1=>Phase::Move,
+      2=>Phase::Up,
+      3=>Phase::Cancel,
+      _ => return None,
+    })
+  }
+  pub fn as_u8(self)->u8 {
+    match self {
+      Phase::Down=>0,
+      Phase::Move=>1,
+      Phase::Up=>2,
+      Phase::Cancel=>3,
+    }
+  }
+}
+
+///A single pointer within a `'mtch'` multi-touch packet.
+#[derive(Clone,Debug)]
+pub struct Contact {
+  ///Identifies the pointer from its `Down` until its `Up` or `Cancel`.
+  pub id: u32,
+  pub phase: Phase,
+  pub ev: MouseMove,
+}
+
+///A touch sample within a `'btch'` packet.
+#[derive(Clone,Debug)]
+pub struct Sample {
+  ///Device timestamp, in microseconds since the device answered the handshake.
+  pub time: u64,
+  pub ev: MouseMove,
+}
+
+///Per-pointer touch state, built from multi-touch contacts.
+///The first pointer to touch the screen while no other is down becomes the primary pointer,
+///which drives the cursor until it is lifted.
+///Once the primary pointer is lifted, the next pointer to move while it is the only one down
+///takes over.
+#[derive(Default)]
+pub struct Contacts {
+  active: BTreeMap<u32,MouseMove>,
+  primary: Option<u32>,
+}
+impl Contacts {
+  pub fn new()->Contacts {Contacts::default()}
+  
+  ///Update pointer state with a contact.
+  ///Returns the contact if it belongs to the primary pointer.
+  pub fn update(&mut self,contact: Contact)->Option<Contact> {
+    let id=contact.id;
+    match contact.phase {
+      Phase::Down | Phase::Move=>{
+        if contact.phase==Phase::Move && !self.active.contains_key(&id) {
+          println!("pointer {} moved without touching down first",id);
+        }
+        self.active.insert(id,contact.ev.clone());
+        if self.primary.is_none() && self.active.len()==1 {
+          self.primary=Some(id);
+        }
+      },
+      Phase::Up | Phase::Cancel=>{
+        if self.active.remove(&id).is_none() {
+          println!("pointer {} lifted without touching down first",id);
+        }
+      },
+    }
+    if self.primary==Some(id) {
+      if contact.phase==Phase::Up || contact.phase==Phase::Cancel {
+        self.primary=None;
+      }
+      Some(contact)
+    }else{
+      None
+    }
+  }
+  
+  ///Forget all pointers, as if they had all been cancelled.
+  pub fn clear(&mut self) {
+    self.active.clear();
+    self.primary=None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rect::{Pair};
+  
+  ///Feed `(id,phase)` contacts, checking which ones drive the cursor.
+  fn run(contacts: &mut Contacts,steps: &[(u32,Phase,bool)]) {
+    for (idx,&(id,phase,primary)) in steps.iter().enumerate() {
+      let ev=MouseMove{pos: Pair([id as f32,idx as f32]),pressure: 1.0,size: 0.0};
+      let out=contacts.update(Contact{id,phase,ev});
+      assert_eq!(out.is_some(),primary,"step {}: pointer {} {:?}",idx,id,phase);
+      if let Some(out)=out {
+        assert_eq!((out.id,out.phase),(id,phase));
+      }
+    }
+  }
+  
+  #[test]
+  fn primary_pointer() {
+    use self::Phase::*;
+    let table: &[&[(u32,Phase,bool)]]=&[
+      //A single finger
+      &[(1,Down,true),(1,Move,true),(1,Up,true)],
+      //A second finger never takes over
+      &[(1,Down,true),(2,Down,false),(2,Move,false),(1,Move,true),(2,Up,false),(1,Up,true)],
+      //After the primary lifts, the last pointer left takes over as soon as it moves
+      &[(1,Down,true),(2,Down,false),(1,Up,true),(2,Move,true),(3,Down,false),(2,Up,true),(3,Up,false)],
+      &[
+        (1,Down,true),(2,Down,false),(3,Down,false),(1,Cancel,true),(2,Move,false),(3,Move,false),
+        (2,Up,false),(3,Move,true),(3,Up,true),(4,Down,true),(4,Cancel,true),
+      ],
+      //Reused ids are new pointers
+      &[(1,Down,true),(1,Up,true),(1,Down,true),(2,Down,false),(1,Up,true),(1,Down,false)],
+      &[(1,Down,true),(2,Down,false),(2,Up,false),(2,Down,false),(1,Up,true),(2,Up,false),(2,Down,true)],
+      //Moves without a down are tracked, and become primary when alone
+      &[(5,Move,true),(5,Up,true),(5,Up,false)],
+    ];
+    for steps in table.iter() {
+      run(&mut Contacts::new(),steps);
+    }
+  }
+  
+  #[test]
+  fn clear_forgets_pointers() {
+    use self::Phase::*;
+    let mut contacts=Contacts::new();
+    run(&mut contacts,&[(1,Down,true),(2,Down,false)]);
+    contacts.clear();
+    run(&mut contacts,&[(2,Down,true),(1,Up,false),(2,Up,true)]);
+  }
+}
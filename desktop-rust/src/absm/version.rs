@@ -0,0 +1,245 @@
+use absm::capability::{Capability,Capabilities};
+
+///A packet or header feature of the protocol, introduced in some minor version.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Feature {
+  ///`'mmov'` and `'tuch'` touch packets.
+  TouchPacket,
+  ///`'keyp'` keypress packets.
+  KeyPacket,
+  ///`'ping'` and `'repl'` packets.
+  PingPacket,
+  ///`'password'`, `'client_name'`, `'frame_delay'` and `'update_delay'` handshake-open headers.
+  OpenHeaders,
+  ///`'screen_res'` server-info header.
+  ScreenResHeader,
+  ///`'mtch'` multi-touch packets.
+  MultiTouchPacket,
+  ///`'pen '` stylus packets.
+  PenPacket,
+  ///`'btch'` batched sample packets.
+  BatchPacket,
+  ///Device timestamps in `'repl'` packets.
+  ReplyTimestamp,
+  ///`'capabilities'` handshake header.
+  CapabilitiesHeader,
+  ///Device identity, density, ranges, sample rate, rotation and insets server-info headers.
+  DeviceInfoHeaders,
+  ///`'coordinate_space'` server-info header.
+  CoordinateSpaceHeader,
+  ///`'bye '` and `'kpal'` packets and `'keepalive'` headers.
+  ClosePackets,
+  ///`'seqn'` packets.
+  SequencePacket,
+  ///`'rdnt'` packets and `'redundancy'` headers.
+  RedundantPacket,
+  ///`'cmov'` and `'dmov'` packets.
+  CompactPacket,
+}
+impl Feature {
+  pub const ALL: &'static [Feature]=&[
+    Feature::TouchPacket,
+    Feature::KeyPacket,
+    Feature::PingPacket,
+    Feature::OpenHeaders,
+    Feature::ScreenResHeader,
+    Feature::MultiTouchPacket,
+    Feature::PenPacket,
+    Feature::BatchPacket,
+    Feature::ReplyTimestamp,
+    Feature::CapabilitiesHeader,
+    Feature::DeviceInfoHeaders,
+    Feature::CoordinateSpaceHeader,
+    Feature::ClosePackets,
+    Feature::SequencePacket,
+    Feature::RedundantPacket,
+    Feature::CompactPacket,
+  ];
+  
+  ///The minor version that introduced this feature.
+  pub fn since(self)->u16 {
+    match self {
+      Feature::TouchPacket | Feature::KeyPacket | Feature::PingPacket=>0,
+      Feature::OpenHeaders | Feature::ScreenResHeader=>0,
+      Feature::MultiTouchPacket=>1,
+      Feature::PenPacket=>2,
+      Feature::BatchPacket=>3,
+      Feature::ReplyTimestamp=>4,
+      Feature::CapabilitiesHeader=>5,
+      Feature::DeviceInfoHeaders=>6,
+      Feature::CoordinateSpaceHeader=>7,
+      Feature::ClosePackets=>8,
+      Feature::SequencePacket=>9,
+      Feature::RedundantPacket=>10,
+      Feature::CompactPacket=>11,
+    }
+  }
+  ///Whether this feature exists in the given minor version.
+  pub fn available(self,minor: u16)->bool {
+    self.since()<=minor
+  }
+  ///The capability that guards this feature, if it is optional.
+  pub fn capability(self)->Option<Capability> {
+    match self {
+      Feature::MultiTouchPacket=>Some(Capability::MultiTouch),
+      Feature::PenPacket=>Some(Capability::Pen),
+      Feature::BatchPacket=>Some(Capability::Batch),
+      Feature::ReplyTimestamp=>Some(Capability::ClockSync),
+      Feature::SequencePacket=>Some(Capability::Sequence),
+      Feature::CompactPacket=>Some(Capability::Compact),
+      _ => None,
+    }
+  }
+  pub fn name(self)->&'static str {
+    match self {
+      Feature::TouchPacket=>"'mmov' packet",
+      Feature::KeyPacket=>"'keyp' packet",
+      Feature::PingPacket=>"'ping' packet",
+      Feature::OpenHeaders=>"handshake-open headers",
+      Feature::ScreenResHeader=>"'screen_res' header",
+      Feature::MultiTouchPacket=>"'mtch' packet",
+      Feature::PenPacket=>"'pen ' packet",
+      Feature::BatchPacket=>"'btch' packet",
+      Feature::ReplyTimestamp=>"'repl' timestamps",
+      Feature::CapabilitiesHeader=>"'capabilities' header",
+      Feature::DeviceInfoHeaders=>"device info headers",
+      Feature::CoordinateSpaceHeader=>"'coordinate_space' header",
+      Feature::ClosePackets=>"'bye ' and 'kpal' packets",
+      Feature::SequencePacket=>"'seqn' packet",
+      Feature::RedundantPacket=>"'rdnt' packet",
+      Feature::CompactPacket=>"'cmov' and 'dmov' packets",
+    }
+  }
+}
+
+///Work out the minor version shared by both sides, warning about any mismatch.
+///Major versions must already match.
+pub fn negotiate(local: (u16,u16),remote: (u16,u16))->u16 {
+  let shared=local.1.min(remote.1);
+  if remote.1<local.1 {
+    let missing: Vec<&str>=Feature::ALL.iter()
+      .filter(|feature| !feature.available(remote.1))
+      .map(|feature| feature.name())
+      .collect();
+    println!(
+      "warning: device speaks absM {}.{}, older than local {}.{}, unavailable features: {}",
+      remote.0,remote.1,local.0,local.1,missing.join(", ")
+    );
+  }else if remote.1>local.1 {
+    println!(
+      "warning: device speaks absM {}.{}, newer than local {}.{}, features added since are not supported",
+      remote.0,remote.1,local.0,local.1
+    );
+  }
+  shared
+}
+
+///The capabilities assumed for a remote that sent no `'capabilities'` header.
+///Before the header existed every feature of the shared minor version was assumed, since
+///then a missing header means no optional features.
+pub fn implied_capabilities(minor: u16)->Capabilities {
+  if Feature::CapabilitiesHeader.available(minor) {
+    return Capabilities::none();
+  }
+  Feature::ALL.iter()
+    .filter(|feature| feature.available(minor))
+    .filter_map(|feature| feature.capability())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  
+  #[test]
+  fn features_per_minor_version() {
+    use self::Feature::*;
+    let table: &[(u16,&[Feature])]=&[
+      (0,&[TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader]),
+      (1,&[TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket]),
+      (2,&[TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket]),
+      (3,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,
+      ]),
+      (4,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,
+      ]),
+      (5,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,
+      ]),
+      (6,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,DeviceInfoHeaders,
+      ]),
+      (7,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,DeviceInfoHeaders,CoordinateSpaceHeader,
+      ]),
+      (8,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,DeviceInfoHeaders,CoordinateSpaceHeader,
+        ClosePackets,
+      ]),
+      (9,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,DeviceInfoHeaders,CoordinateSpaceHeader,
+        ClosePackets,SequencePacket,
+      ]),
+      (10,&[
+        TouchPacket,KeyPacket,PingPacket,OpenHeaders,ScreenResHeader,MultiTouchPacket,PenPacket,
+        BatchPacket,ReplyTimestamp,CapabilitiesHeader,DeviceInfoHeaders,CoordinateSpaceHeader,
+        ClosePackets,SequencePacket,RedundantPacket,
+      ]),
+      (11,Feature::ALL),
+    ];
+    for &(minor,expected) in table {
+      for &feature in Feature::ALL {
+        assert_eq!(
+          feature.available(minor),expected.contains(&feature),
+          "{:?} in minor version {}",feature,minor
+        );
+      }
+    }
+  }
+  
+  #[test]
+  fn shared_minor_version() {
+    let table=[
+      ((1,5),(1,0),0),
+      ((1,5),(1,5),5),
+      ((1,5),(1,9),5),
+      ((1,0),(1,3),0),
+    ];
+    for &(local,remote,shared) in table.iter() {
+      assert_eq!(negotiate(local,remote),shared,"local {:?} remote {:?}",local,remote);
+    }
+  }
+  
+  #[test]
+  fn capabilities_implied_by_minor_version() {
+    use absm::capability::Capability::*;
+    let table: &[(u16,&[Capability])]=&[
+      (0,&[]),
+      (1,&[MultiTouch]),
+      (2,&[MultiTouch,Pen]),
+      (3,&[MultiTouch,Pen,Batch]),
+      (4,&[MultiTouch,Pen,Batch,ClockSync]),
+      (5,&[]),
+      (6,&[]),
+      (7,&[]),
+      (8,&[]),
+      (9,&[]),
+      (10,&[]),
+      (11,&[]),
+    ];
+    for &(minor,expected) in table {
+      let implied=implied_capabilities(minor);
+      for &cap in Capability::ALL {
+        assert_eq!(implied.contains(cap),expected.contains(&cap),"{:?} in minor version {}",cap,minor);
+      }
+    }
+  }
+}
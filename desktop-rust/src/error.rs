@@ -0,0 +1,103 @@
+use std::{
+  io,fmt,
+  error::Error as ErrorTrait,
+};
+use bincode;
+use absm::{Stage};
+use absm::packet::{DecodeError};
+
+pub type Result<T> = ::std::result::Result<T,Error>;
+
+///Any error that can occur while running the client.
+#[derive(Debug)]
+pub enum Error {
+  ///The underlying connection failed or was closed.
+  Io(io::Error),
+  ///The remote speaks an incompatible major `absM` version.
+  Version{local: (u16,u16),remote: (u16,u16)},
+  ///A single packet from the remote could not be decoded.
+  Malformed(DecodeError),
+  ///The remote did not follow the protocol, for example by skipping a handshake step.
+  Protocol(String),
+  ///The connection stayed in the given stage for too long.
+  Timeout(Stage),
+  ///The remote closed the connection with a `'bye '` packet, giving a reason.
+  Closed(String),
+  ///The configuration file could not be used.
+  Config(String),
+  ///Any other error, described by a message.
+  Other(String),
+}
+impl Error {
+  ///How should the client react to this error.
+  pub fn recovery(&self)->Recovery {
+    match self {
+      Error::Io(_)=>Recovery::Retry,
+      Error::Version{..}=>Recovery::Exit,
+      Error::Malformed(_)=>Recovery::Skip,
+      Error::Protocol(_)=>Recovery::Retry,
+      Error::Timeout(_)=>Recovery::Retry,
+      Error::Closed(_)=>Recovery::Retry,
+      Error::Config(_)=>Recovery::Exit,
+      Error::Other(_)=>Recovery::Exit,
+    }
+  }
+}
+impl fmt::Display for Error {
+  fn fmt(&self,f: &mut fmt::Formatter)->fmt::Result {
+    match self {
+      Error::Io(err)=>write!(f,"connection error: {}",err),
+      Error::Version{local,remote}=>write!(
+        f,"abs-m protocol version mismatch: local {}.{} != remote {}.{}",
+        local.0,local.1 , remote.0,remote.1
+      ),
+      Error::Malformed(err)=>write!(f,"malformed packet: {}",err),
+      Error::Protocol(msg)=>write!(f,"protocol error: {}",msg),
+      Error::Timeout(stage)=>write!(f,"timed out in the {} stage",stage),
+      Error::Closed(reason)=>write!(f,"device closed the connection: {}",reason),
+      Error::Config(msg)=>write!(f,"configuration error: {}",msg),
+      Error::Other(msg)=>write!(f,"{}",msg),
+    }
+  }
+}
+impl ErrorTrait for Error {
+  fn source(&self)->Option<&(dyn ErrorTrait+'static)> {
+    match self {
+      Error::Io(err)=>Some(err),
+      Error::Malformed(err)=>Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error)->Error {Error::Io(err)}
+}
+impl From<DecodeError> for Error {
+  fn from(err: DecodeError)->Error {Error::Malformed(err)}
+}
+impl From<bincode::Error> for Error {
+  fn from(err: bincode::Error)->Error {
+    match *err {
+      bincode::ErrorKind::Io(err)=>Error::Io(err),
+      err=>Error::Other(err.to_string()),
+    }
+  }
+}
+impl<'a> From<&'a str> for Error {
+  fn from(msg: &'a str)->Error {Error::Other(msg.to_string())}
+}
+impl From<String> for Error {
+  fn from(msg: String)->Error {Error::Other(msg)}
+}
+
+///What to do after an error.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Recovery {
+  ///Drop the offending packet and keep the session going.
+  Skip,
+  ///Drop the connection and connect again.
+  Retry,
+  ///Nothing sensible can be done, exit the client.
+  Exit,
+}
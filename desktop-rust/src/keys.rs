@@ -0,0 +1,90 @@
+use inputbot::{KeybdKey};
+
+///Map a LÖVE `KeyConstant` name, as sent by the device, to a desktop key.
+///Returns `None` for keys with no desktop equivalent.
+pub fn from_love_key(name: &str)->Option<KeybdKey> {
+  use inputbot::KeybdKey::*;
+  let key=match name {
+    "a"=>AKey, "b"=>BKey, "c"=>CKey, "d"=>DKey, "e"=>EKey, "f"=>FKey, "g"=>GKey,
+    "h"=>HKey, "i"=>IKey, "j"=>JKey, "k"=>KKey, "l"=>LKey, "m"=>MKey, "n"=>NKey,
+    "o"=>OKey, "p"=>PKey, "q"=>QKey, "r"=>RKey, "s"=>SKey, "t"=>TKey, "u"=>UKey,
+    "v"=>VKey, "w"=>WKey, "x"=>XKey, "y"=>YKey, "z"=>ZKey,
+    "0"=>Numrow0Key, "1"=>Numrow1Key, "2"=>Numrow2Key, "3"=>Numrow3Key, "4"=>Numrow4Key,
+    "5"=>Numrow5Key, "6"=>Numrow6Key, "7"=>Numrow7Key, "8"=>Numrow8Key, "9"=>Numrow9Key,
+    "kp0"=>Numpad0Key, "kp1"=>Numpad1Key, "kp2"=>Numpad2Key, "kp3"=>Numpad3Key,
+    "kp4"=>Numpad4Key, "kp5"=>Numpad5Key, "kp6"=>Numpad6Key, "kp7"=>Numpad7Key,
+    "kp8"=>Numpad8Key, "kp9"=>Numpad9Key,
+    "f1"=>F1Key, "f2"=>F2Key, "f3"=>F3Key, "f4"=>F4Key, "f5"=>F5Key, "f6"=>F6Key,
+    "f7"=>F7Key, "f8"=>F8Key, "f9"=>F9Key, "f10"=>F10Key, "f11"=>F11Key, "f12"=>F12Key,
+    "backspace"=>BackspaceKey,
+    "tab"=>TabKey,
+    "return" | "kpenter"=>EnterKey,
+    "escape"=>EscapeKey,
+    "space"=>SpaceKey,
+    "home"=>HomeKey,
+    "left"=>LeftKey,
+    "up"=>UpKey,
+    "right"=>RightKey,
+    "down"=>DownKey,
+    "insert"=>InsertKey,
+    "delete"=>DeleteKey,
+    "numlock"=>NumLockKey,
+    "scrolllock"=>ScrollLockKey,
+    "capslock"=>CapsLockKey,
+    "lshift"=>LShiftKey,
+    "rshift"=>RShiftKey,
+    "lctrl"=>LControlKey,
+    "rctrl"=>RControlKey,
+    name=>return platform_key(name).map(OtherKey),
+  };
+  Some(key)
+}
+
+///Keys not covered by `inputbot`, as raw X11 keysyms.
+#[cfg(target_os="linux")]
+fn platform_key(name: &str)->Option<u64> {
+  Some(match name {
+    "volumeup"=>0x1008FF13,
+    "volumedown"=>0x1008FF11,
+    "mute" | "audiomute"=>0x1008FF12,
+    "audioplay"=>0x1008FF14,
+    "audiostop"=>0x1008FF15,
+    "audioprev"=>0x1008FF16,
+    "audionext"=>0x1008FF17,
+    "end"=>0xFF57,
+    "pageup"=>0xFF55,
+    "pagedown"=>0xFF56,
+    "lalt"=>0xFFE9,
+    "ralt"=>0xFFEA,
+    "lgui"=>0xFFEB,
+    "rgui"=>0xFFEC,
+    _ => return None,
+  })
+}
+
+///Keys not covered by `inputbot`, as raw Windows virtual-key codes.
+#[cfg(target_os="windows")]
+fn platform_key(name: &str)->Option<u64> {
+  Some(match name {
+    "volumeup"=>0xAF,
+    "volumedown"=>0xAE,
+    "mute" | "audiomute"=>0xAD,
+    "audioplay"=>0xB3,
+    "audiostop"=>0xB2,
+    "audioprev"=>0xB1,
+    "audionext"=>0xB0,
+    "end"=>0x23,
+    "pageup"=>0x21,
+    "pagedown"=>0x22,
+    "lalt"=>0xA4,
+    "ralt"=>0xA5,
+    "lgui"=>0x5B,
+    "rgui"=>0x5C,
+    _ => return None,
+  })
+}
+
+#[cfg(not(any(target_os="linux",target_os="windows")))]
+fn platform_key(_name: &str)->Option<u64> {
+  None
+}
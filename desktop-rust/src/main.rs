@@ -14,28 +14,38 @@ use std::{
   io::{self,BufRead,BufReader},
   fs::{File},
   cmp::{Ordering},
+  collections::{BTreeMap},
   env,
   ops::{self,RangeInclusive},
   path::{Path},
+  process,thread,
 };
 use byteorder::{NetworkEndian,ByteOrder,ReadBytesExt};
-use inputbot::{MouseCursor};
+use inputbot::{MouseCursor,MouseButton,KeybdKey};
 use rect::*;
 use network::{Remote};
-use absm::{AbsmSession,ServerInfo};
+use absm::{AbsmSession,ServerInfo,CoordinateSpace};
 
 mod prelude {
   pub use std::error::Error as ErrorTrait;
-  pub type Error = Box<ErrorTrait>;
-  pub type Result<T> = ::std::result::Result<T,Error>;
+  pub use error::{Error,Result,Recovery};
   
   pub use std::{
     io::{Write,Read},
-    fmt,mem,
+    fmt,
   };
   pub use serde::{Serialize,Deserialize};
   pub use network::{Connection,LocalBuffer,NetBuffer};
   
+  ///Convert a configuration duration in seconds, clamping negative values to zero.
+  pub fn duration_from_secs(secs: f32)->::std::time::Duration {
+    ::std::time::Duration::from_micros((secs.max(0.0)*1_000_000.0) as u64)
+  }
+  ///Convert a duration to fractional seconds.
+  pub fn secs_from_duration(duration: ::std::time::Duration)->f64 {
+    duration.as_secs() as f64+duration.subsec_nanos() as f64*1e-9
+  }
+  
   pub enum Never {}
   impl Never {
     fn as_never(&self)->! {unsafe{::std::hint::unreachable_unchecked()}}
@@ -51,10 +61,16 @@ mod prelude {
 
 #[macro_use]
 mod rect;
+mod error;
 mod network;
 mod absm;
+mod keys;
 
 pub struct Setup {
+  ///Coordinate space of incoming positions, converted to `Pixels` before mapping.
+  pub space: CoordinateSpace,
+  ///Device screen resolution, used to convert incoming positions.
+  pub screen_res: Pair<f32>,
   ///Map from input device coordinates to output client coordinates.
   pub mapping: Mapping,
   ///Specify a minimum and a maximum on the final client coordinates.
@@ -64,15 +80,35 @@ pub struct Setup {
   pub pressure: [f32; 2],
   ///Specify a range of sizes, similarly to `pressure`.
   pub size: [f32; 2],
+  ///Hold the left mouse button while a pen touches the screen.
+  pub pen_clicks: bool,
+  ///Hold the right mouse button while the primary pen barrel button is pressed.
+  pub pen_barrel_right_click: bool,
+  ///Mouse buttons currently held down on behalf of the device.
+  held: Vec<MouseButton>,
+  ///Keys currently held down on behalf of the device.
+  held_keys: Vec<KeybdKey>,
+  ///The last pen tool seen.
+  pen_tool: Option<PenTool>,
 }
 impl Setup {
   fn new(info: &ServerInfo,config: &Config)->Setup {
     //Target area is set immutably by the config
     let target=config.target;
-    //Start off with source area as the entire device screen
+    //Start off with source area as the entire device screen, in pixels with the origin at the top-left corner
     //Source area is more mutable than target area
     let mut source=Rect{min: Pair([0.0; 2]),max: info.server_screen_res};
     println!("device screen area: {}",source);
+    info.log_device();
+    
+    //Leave out system UI such as notches and navigation bars
+    if config.exclude_safe_insets {
+      if let Some([left,top,right,bottom])=info.safe_insets {
+        source.min=Pair([source.min[Axis::X]+left,source.min[Axis::Y]+top]);
+        source.max=Pair([source.max[Axis::X]-right,source.max[Axis::Y]-bottom]);
+        println!("excluding system UI insets, device screen area is now {}",source);
+      }
+    }
     
     //Correct any device rotations
     if config.correct_device_orientation {
@@ -154,24 +190,115 @@ impl Setup {
     
     println!("clipping target to {}",config.clip);
     println!("only allowing touches with pressures inside {:?} and sizes inside {:?}",pressure,size);
+    //Warn about filters that would drop every touch from this device
+    let check_range=|name: &str,filter: [f32; 2],reported: Option<[f32; 2]>| if let Some(reported)=reported {
+      if filter[0]>reported[1] || filter[1]<reported[0] {
+        println!(
+          "warning: {} filter {:?} lies outside the range {:?} reported by the device, every touch will be ignored",
+          name,filter,reported
+        );
+      }
+    };
+    check_range("pressure",pressure,info.pressure_range);
+    check_range("size",size,info.size_range);
     
     Setup{
+      space: info.coordinate_space,
+      screen_res: info.server_screen_res,
       mapping: source.normalizer().chain(&target.map(|int| int as f32).denormalizer()),
       clip: config.clip,
       pressure,size,
+      pen_clicks: config.pen_clicks,
+      pen_barrel_right_click: config.pen_barrel_right_click,
+      held: Vec::new(),
+      held_keys: Vec::new(),
+      pen_tool: None,
     }
   }
   
-  fn consume(&mut self,ev: MouseMove) {
-    if ev.pressure<self.pressure[0] || ev.pressure>self.pressure[1] {return}
-    if ev.size<self.size[0] || ev.size>self.size[1] {return}
-    let pos=self.mapping.apply(ev.pos);
+  ///Whether a touch is within the allowed pressure and size ranges.
+  fn accepts(&self,ev: &MouseMove)->bool {
+    ev.pressure>=self.pressure[0] && ev.pressure<=self.pressure[1] &&
+      ev.size>=self.size[0] && ev.size<=self.size[1]
+  }
+  
+  fn move_cursor(&mut self,pos: Pair<f32>) {
+    let pos=self.space.to_pixels(pos,self.screen_res);
+    let pos=self.mapping.apply(pos);
     let adjusted=pair!(i=> (pos[i] as i32).max(self.clip.min[i]).min(self.clip.max[i]));
     MouseCursor.move_abs(adjusted[Axis::X],adjusted[Axis::Y]);
   }
+  
+  fn consume(&mut self,ev: MouseMove) {
+    if !self.accepts(&ev) {return}
+    self.move_cursor(ev.pos);
+  }
+  
+  fn consume_pen(&mut self,pen: PenEvent) {
+    //Hovering always moves the cursor, but touches are filtered like finger touches
+    if pen.touching && !self.accepts(&pen.ev) {
+      //A filtered touch no longer presses, so let go of any button without moving the cursor
+      self.set_button(MouseButton::LeftButton,false);
+      self.set_button(MouseButton::RightButton,false);
+      return;
+    }
+    if self.pen_tool!=Some(pen.tool) {
+      println!("pen tool is now {:?}",pen.tool);
+      self.pen_tool=Some(pen.tool);
+    }
+    self.move_cursor(pen.ev.pos);
+    let right=self.pen_barrel_right_click && pen.buttons&0x1!=0;
+    let left=self.pen_clicks && pen.touching && !right;
+    self.set_button(MouseButton::LeftButton,left);
+    self.set_button(MouseButton::RightButton,right);
+  }
+  
+  ///Press or release a mouse button, only if its state changes.
+  fn set_button(&mut self,button: MouseButton,down: bool) {
+    let held=self.held.contains(&button);
+    if down && !held {
+      button.press();
+      self.held.push(button);
+    }else if !down && held {
+      button.release();
+      self.held.retain(|&other| other!=button);
+    }
+  }
+  
+  ///Release any mouse buttons and keys held on behalf of the device.
+  fn release_all(&mut self) {
+    for button in self.held.drain(..) {
+      button.release();
+    }
+    for key in self.held_keys.drain(..) {
+      key.release();
+    }
+  }
+  
+  fn consume_key(&mut self,ev: KeyPress) {
+    //Prefer the layout-dependent key name, falling back to the physical scancode
+    match keys::from_love_key(&ev.key).or_else(|| keys::from_love_key(&ev.scancode)) {
+      Some(key)=>{
+        println!(
+          "key '{}' {} (modifiers {:#x})",
+          ev.key,if ev.down {"down"}else{"up"},ev.modifiers
+        );
+        if ev.down {
+          key.press();
+          if !self.held_keys.contains(&key) {
+            self.held_keys.push(key);
+          }
+        }else{
+          key.release();
+          self.held_keys.retain(|&other| other!=key);
+        }
+      },
+      None=>println!("no desktop key for device key '{}' (scancode '{}')",ev.key,ev.scancode),
+    }
+  }
 }
 
-#[derive(Deserialize,Serialize)]
+#[derive(Deserialize,Serialize,Clone)]
 #[serde(default)]
 pub struct Config {
   ///The target area to be mapped, in screen pixels.
@@ -192,13 +319,79 @@ pub struct Config {
   pub pressure_range: [Option<f32>; 2],
   ///Only allow touches within this size range to go through.
   pub size_range: [Option<f32>; 2],
+  ///Leave out the system UI insets reported by the device, such as notches and navigation bars.
+  pub exclude_safe_insets: bool,
+  ///Hold the left mouse button while a pen touches the screen.
+  ///When off, pen contact moves the cursor without pressing a button.
+  pub pen_clicks: bool,
+  ///Hold the right mouse button while the pen barrel button is pressed.
+  pub pen_barrel_right_click: bool,
   ///Connect to this remote.
   pub remote: Remote,
+  ///Password expected by the device, if it has one set.
+  pub password: String,
+  ///Name to identify this client to the device.
+  pub client_name: String,
+  ///Ask the device to render frames this many seconds apart.
+  pub frame_delay: Option<f32>,
+  ///Ask the device to poll for events this many seconds apart.
+  pub update_delay: Option<f32>,
+  ///Additional headers to send in the handshake, as opaque string values.
+  ///Names of headers set by the fields above are reserved and skipped with a warning.
+  pub extra_headers: BTreeMap<String,String>,
+  ///Drop any packet from the device larger than this many bytes.
+  pub max_packet_size: u32,
+  ///Ping the device every this many seconds to measure latency, or never if `None`.
+  pub ping_interval: Option<f32>,
+  ///Drop the connection if a ping goes unanswered for this many seconds.
+  pub ping_timeout: f32,
+  ///Log latency statistics every this many seconds, or never if `None`.
+  pub latency_log_interval: Option<f32>,
+  ///Seconds to wait for the device to accept a TCP connection.
+  pub connect_timeout: f32,
+  ///Seconds to wait for the device to reply to the handshake.
+  pub handshake_timeout: f32,
+  ///Ask the device to exchange keepalives every this many seconds, or never if `None`.
+  pub keepalive_interval: Option<f32>,
+  ///Consider the device gone after this many keepalive intervals without hearing from it.
+  pub keepalive_misses: u32,
+  ///Let the device report every finger as a separate multi-touch contact.
+  ///Contacts skip redundant samples, batching, the jitter buffer and compact touches, and the
+  ///cursor only follows the first finger anyway.
+  pub multitouch: bool,
+  ///Ask the device to repeat this many previous samples in every touch packet, to make up for
+  ///packets lost over UDP, or never if `None`.
+  pub redundancy: Option<u8>,
+  ///Have the device send touches in the compact fixed-point format instead of timestamped
+  ///batches, which is about half the size and precise to a small fraction of a pixel.
+  ///Compact touches carry no timestamps, so they are not used along with the jitter buffer.
+  pub compact_touches: bool,
+  ///Hold timestamped samples for a short delay and release them at their original spacing,
+  ///smoothing out bursty delivery over WiFi.
+  pub jitter_buffer: bool,
+  ///Delay samples in the jitter buffer by this many seconds, or adapt to the jitter if `None`.
+  pub jitter_delay: Option<f32>,
+  ///Never delay samples in the jitter buffer by more than this many seconds when adapting.
+  pub jitter_max_delay: f32,
+  ///When falling behind, apply only the newest position among the packets already waiting,
+  ///keeping touch transitions and key events.
+  pub coalesce: bool,
+  ///Drop the connection if nothing arrives from the device for this many seconds.
+  ///The device only sends events while it is being used, so this is disabled by default.
+  pub idle_timeout: Option<f32>,
   ///When ADB port forwarding, map this port on the device.
   pub android_usb_port: u16,
   ///Whether to attempt to do ADB port forwarding automatically.
   ///The android device needs to have `USB Debugging` enabled.
   pub android_attempt_usb_connection: bool,
+  ///Seconds to wait after the first failed reconnection attempt, the first one being made right
+  ///away.
+  ///The delay doubles after every further failed attempt.
+  pub reconnect_delay: f32,
+  ///Upper bound for the delay between reconnection attempts, in seconds.
+  pub reconnect_max_delay: f32,
+  ///Give up after this many failed reconnection attempts in a row, or never if `None`.
+  pub reconnect_attempts: Option<u32>,
 }
 impl Default for Config {
   fn default()->Config {
@@ -212,47 +405,113 @@ impl Default for Config {
       keep_aspect_ratio: true,
       pressure_range: [None; 2],
       size_range: [None; 2],
+      exclude_safe_insets: true,
+      pen_clicks: true,
+      pen_barrel_right_click: true,
       remote: Remote::Tcp("localhost".into(),8517),
+      password: String::new(),
+      client_name: "desktop-rust".into(),
+      frame_delay: Some(0.25),
+      update_delay: None,
+      extra_headers: BTreeMap::new(),
+      max_packet_size: 65536,
+      ping_interval: Some(1.0),
+      ping_timeout: 5.0,
+      latency_log_interval: Some(60.0),
+      connect_timeout: 5.0,
+      handshake_timeout: 2.0,
+      keepalive_interval: Some(1.0),
+      keepalive_misses: 3,
+      multitouch: false,
+      redundancy: None,
+      compact_touches: false,
+      jitter_buffer: false,
+      jitter_delay: None,
+      jitter_max_delay: 0.1,
+      coalesce: false,
+      idle_timeout: None,
       android_usb_port: 8517,
       android_attempt_usb_connection: true,
+      reconnect_delay: 0.5,
+      reconnect_max_delay: 8.0,
+      reconnect_attempts: None,
     }
   }
 }
 impl Config {
-  fn load_path(cfg_path: &str)->Config {
+  fn load_path(cfg_path: &str)->Result<Config> {
     println!("loading config file at '{}'",cfg_path);
     match File::open(&cfg_path) {
       Err(err)=>{
         println!("failed to open config at '{}', using defaults:\n {}",cfg_path,err);
         let config=Config::default();
-        match File::create(&cfg_path) {
-          Err(err)=>{
-            println!("failed to create config file on '{}':\n {}",cfg_path,err);
-          },
-          Ok(mut file)=>{
-            let cfg=ron::ser::to_string_pretty(&config,Default::default()).expect("error serializing default config");
-            file.write_all(cfg.as_bytes()).expect("failed to write config file");
-            println!("created default config file on '{}'",cfg_path);
-          },
+        let written=ron::ser::to_string_pretty(&config,Default::default())
+          .map_err(|err| Error::Config(format!("error serializing default config: {}",err)))
+          .and_then(|cfg| {
+            let mut file=File::create(cfg_path)?;
+            file.write_all(cfg.as_bytes())?;
+            Ok(())
+          });
+        match written {
+          Ok(())=>println!("created default config file on '{}'",cfg_path),
+          Err(err)=>println!("failed to create config file on '{}':\n {}",cfg_path,err),
         }
-        config
+        Ok(config)
       },
       Ok(file)=>{
-        let config=ron::de::from_reader(file).expect("malformed configuration file");
+        let config=ron::de::from_reader(file).map_err(|err| Error::Config(format!(
+          "malformed configuration file '{}': {}",cfg_path,err
+        )))?;
         println!("loaded config file '{}'",cfg_path);
-        config
+        Ok(config)
       },
     }
   }
 }
 
-#[derive(Deserialize)]
-struct MouseMove {
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct MouseMove {
   pos: Pair<f32>,
   pressure: f32,
   size: f32,
 }
 
+///A stylus event, see the `'pen '` packet in `absm-protocol.md`.
+#[derive(Debug,Clone)]
+pub struct PenEvent {
+  ///Position, pressure and size, as in touch events.
+  ev: MouseMove,
+  ///Distance from the screen, zero while touching.
+  hover: f32,
+  ///Tilt along the X and Y axes, in radians.
+  tilt: Pair<f32>,
+  ///Rotation around the pen axis, in radians.
+  orientation: f32,
+  tool: PenTool,
+  ///Whether the pen touches the screen, otherwise it is hovering.
+  touching: bool,
+  ///Barrel button bitfield, with the primary button in the lowest bit.
+  buttons: u8,
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum PenTool {
+  Pen,
+  Eraser,
+}
+
+#[derive(Debug)]
+pub struct KeyPress {
+  ///Device key name, following LÖVE `KeyConstant` names.
+  key: String,
+  ///Device physical key name, following LÖVE `Scancode` names.
+  scancode: String,
+  ///Whether the key was pressed or released.
+  down: bool,
+  ///Modifier bitfield, see `absm-protocol.md`.
+  modifiers: u16,
+}
+
 fn get_screen_resolution()->Pair<i32> {
   let screenshot=screenshot::get_screenshot(0).expect("failed to get screen dimensions");
   Pair([screenshot.width() as i32,screenshot.height() as i32])
@@ -302,6 +561,60 @@ fn try_adb_forward<P: AsRef<Path>>(path: P,config: &Config)->Result<()> {
   }
 }
 
+///Open the adb tunnel to the device, if enabled.
+fn adb_forward(exec_path: &str,config: &Config) {
+  if config.android_attempt_usb_connection {
+    let ok=try_adb_forward(Path::new(exec_path).with_file_name("adb"),config)
+      .or_else(|_err| try_adb_forward("adb",config));
+    match ok {
+      Ok(())=>println!(
+        "opened communication tunnel to android device"
+      ),
+      Err(_err)=>println!(
+        "failed to open communication to android device, is USB Debugging enabled?"
+      ),
+    }
+  }else{
+    println!("usb android device connection is disabled");
+  }
+}
+
+///Shortest delay between reconnection attempts, in seconds.
+const MIN_RECONNECT_DELAY: f32=0.05;
+
+///Run `attempt` until it succeeds, waiting an exponentially growing delay and redoing adb port
+///forwarding between attempts.
+///The delay never drops below `MIN_RECONNECT_DELAY`, so that a zero delay can't spin.
+///Exits the process on unrecoverable errors or after `reconnect_attempts` failures.
+fn retry_with_backoff<T,F>(exec_path: &str,config: &Config,mut attempt: F)->T
+  where F: FnMut()->Result<T>
+{
+  let mut delay=config.reconnect_delay.max(MIN_RECONNECT_DELAY);
+  let mut failures=0;
+  loop {
+    let err=match attempt() {
+      Ok(t)=>return t,
+      Err(err)=>err,
+    };
+    if err.recovery()==Recovery::Exit {
+      println!("{}",err);
+      process::exit(1);
+    }
+    failures+=1;
+    println!("failed to connect to device: {}",err);
+    if let Some(max)=config.reconnect_attempts {
+      if failures>=max {
+        println!("giving up after {} failed attempts",failures);
+        process::exit(1);
+      }
+    }
+    println!("retrying in {:.1}s",delay);
+    thread::sleep(duration_from_secs(delay));
+    delay=(delay*2.0).min(config.reconnect_max_delay).max(MIN_RECONNECT_DELAY);
+    adb_forward(exec_path,config);
+  }
+}
+
 fn main() {
   //Parse arguments
   let exec_path;
@@ -313,27 +626,45 @@ fn main() {
   }
   
   //Load configuration
-  let config=Config::load_path(&cfg_path);
+  let config=match Config::load_path(&cfg_path) {
+    Ok(config)=>config,
+    Err(err)=>{
+      println!("{}",err);
+      process::exit(1);
+    },
+  };
   
   //Try port forwarding using adb
-  if config.android_attempt_usb_connection {
-    let ok=try_adb_forward(&Path::new(&exec_path).with_file_name("adb"),&config)
-      .or_else(|_err| try_adb_forward("adb",&config));
-    match ok {
-      Ok(())=>println!(
-        "opened communication tunnel to android device"
-      ),
-      Err(_err)=>println!(
-        "failed to open communication to android device, is USB Debugging enabled?"
-      ),
-    }
-  }else{
-    println!("usb android device connection is disabled");
-  }
+  adb_forward(&exec_path,&config);
   
-  let mut session=AbsmSession::new(config);
+  let mut session=AbsmSession::new(config.clone());
+  retry_with_backoff(&exec_path,&config,|| session.reconnect());
   loop {
-    session.wait_for_event();
+    if let Err(err)=session.wait_for_event() {
+      match err.recovery() {
+        Recovery::Skip=>println!("skipping packet: {}",err),
+        Recovery::Retry=>{
+          //The session is now disconnected, but keeps the last setup around
+          println!("lost connection to device: {}",err);
+          if let Some(latency)=session.latency() {
+            println!("latency before disconnecting: {}",latency);
+          }
+          if let Some(loss)=session.loss() {
+            println!("{}",loss);
+          }
+          if let Some(estimate)=session.clock_estimate() {
+            println!("{}",estimate);
+          }
+          adb_forward(&exec_path,&config);
+          retry_with_backoff(&exec_path,&config,|| session.reconnect());
+          println!("reconnected to device");
+        },
+        Recovery::Exit=>{
+          println!("{}",err);
+          process::exit(1);
+        },
+      }
+    }
   }
   
   /*
@@ -1,9 +1,12 @@
 use prelude::*;
 use std::{
-  net::{TcpStream,UdpSocket},
+  io,
+  net::{TcpStream,UdpSocket,ToSocketAddrs},
+  time::{Duration},
   cell::{RefCell},
 };
 use bincode;
+use absm::packet::{DecodeError};
 
 pub fn decode_from<T,R: Read>(read: R)->Result<T> where for<'a> T: Deserialize<'a> {
   let mut cfg=bincode::config();
@@ -16,31 +19,44 @@ pub fn encode_into<T: Serialize,W: Write>(write: W,obj: &T)->Result<()> {
   Ok(cfg.serialize_into(write,obj)?)
 }
 
-pub fn f32_to_bytes(float: f32)->[u8; 4] {
-  let mut bytes=[0; 4];
-  encode_into(&mut bytes[..],&float).unwrap();
-  bytes
-}
-
 #[derive(Serialize,Deserialize,Clone,Debug)]
 pub enum Remote {
   Tcp(String,u16),
   Udp(String,u16),
 }
 impl Remote {
-  pub fn connect(&self)->Result<Box<Connection>> {
+  ///Connect to the remote, refusing to receive packets larger than `max_packet_size` bytes.
+  ///`timeout` bounds the time spent establishing a TCP connection.
+  pub fn connect(&self,max_packet_size: usize,timeout: Duration)->Result<Box<dyn Connection>> {
     match self {
       Remote::Tcp(host,port)=>{
-        let stream=TcpStream::connect((&**host,*port))?;
+        //Try every address the host resolves to
+        let mut last_err=None;
+        let mut stream=None;
+        for addr in (&**host,*port).to_socket_addrs()? {
+          match TcpStream::connect_timeout(&addr,timeout) {
+            Ok(s)=>{
+              stream=Some(s);
+              break;
+            },
+            Err(err)=>last_err=Some(err),
+          }
+        }
+        let stream=match stream {
+          Some(stream)=>stream,
+          None=>return Err(last_err.unwrap_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,"host did not resolve to any address"
+          )).into()),
+        };
         stream.set_nodelay(true)?;
         stream.set_read_timeout(None)?;
         stream.set_nonblocking(false)?;
-        Ok(Box::new(stream))
+        Ok(Box::new(TcpConnection::new(stream,max_packet_size)))
       },
       Remote::Udp(host,port)=>{
         let sock=UdpSocket::bind(("localhost",0))?;
         sock.connect((&**host,*port))?;
-        Ok(Box::new(sock))
+        Ok(Box::new(UdpConnection{sock,max_packet_size}))
       },
     }
   }
@@ -57,6 +73,17 @@ impl fmt::Display for Remote {
 pub trait Connection {
   fn send(&mut self,&[u8])->Result<()>;
   fn recv(&mut self,&mut Vec<u8>)->Result<()>;
+  ///Receive a packet only if one has already arrived, without waiting.
+  ///Returns `false` if there is none.
+  fn try_recv(&mut self,buf: &mut Vec<u8>)->Result<bool>;
+  ///Make `recv` fail with a `WouldBlock` or `TimedOut` I/O error after waiting for `timeout`,
+  ///or wait forever if `None`.
+  fn set_timeout(&mut self,timeout: Option<Duration>)->Result<()>;
+}
+
+///Read timeouts of zero are not allowed, round them up.
+fn nonzero_timeout(timeout: Option<Duration>)->Option<Duration> {
+  timeout.map(|timeout| timeout.max(Duration::from_millis(1)))
 }
 
 ///Call a closure with mutable access to an empty cached network buffer.
@@ -85,46 +112,237 @@ thread_local!{
   static NET_BUFFER: NetBuffer=Default::default();
 }
 
-impl Connection for TcpStream {
+///Packets over a TCP stream, each prefixed by its 4-byte length.
+pub struct TcpConnection {
+  stream: TcpStream,
+  max_packet_size: usize,
+  ///Bytes received from the stream but not yet handed out as packets.
+  pending: Vec<u8>,
+  ///Bytes still to be dropped from the stream, belonging to an oversized packet.
+  discard: usize,
+}
+impl TcpConnection {
+  ///How many bytes to ask the stream for at once.
+  const READ_CHUNK: usize = 4096;
+  
+  pub fn new(stream: TcpStream,max_packet_size: usize)->TcpConnection {
+    TcpConnection{stream,max_packet_size,pending: Vec::with_capacity(Self::READ_CHUNK),discard: 0}
+  }
+  
+  ///Try to split a full packet off the pending bytes.
+  ///Returns `false` if more bytes are needed.
+  fn take_packet(&mut self,buf: &mut Vec<u8>)->Result<bool> {
+    //Drop the remains of an oversized packet first
+    if self.discard>0 {
+      let len=self.discard.min(self.pending.len());
+      self.pending.drain(..len);
+      self.discard-=len;
+      if self.discard>0 {
+        return Ok(false);
+      }
+    }
+    //Read length prefix
+    if self.pending.len()<4 {
+      return Ok(false);
+    }
+    let len: u32=decode_from(&self.pending[..4])?;
+    let len=len as usize;
+    if len>self.max_packet_size {
+      //Skip the packet without buffering it, keeping the stream in sync
+      self.pending.drain(..4);
+      self.discard=len;
+      return Err(DecodeError::TooLarge{len,max: self.max_packet_size}.into());
+    }
+    //Split off packet body
+    if self.pending.len()<4+len {
+      return Ok(false);
+    }
+    buf.clear();
+    buf.extend_from_slice(&self.pending[4..4+len]);
+    self.pending.drain(..4+len);
+    Ok(true)
+  }
+  
+  ///Read some more bytes from the stream into the pending buffer.
+  fn fill(&mut self)->Result<()> {
+    let old_len=self.pending.len();
+    self.pending.resize(old_len+Self::READ_CHUNK,0);
+    let result=self.stream.read(&mut self.pending[old_len..]);
+    self.pending.truncate(old_len+*result.as_ref().unwrap_or(&0));
+    match result {
+      Ok(0)=>Err(io::Error::new(io::ErrorKind::UnexpectedEof,"connection closed by remote").into()),
+      Ok(_)=>Ok(()),
+      Err(ref err) if err.kind()==io::ErrorKind::Interrupted=>Ok(()),
+      Err(err)=>Err(err.into()),
+    }
+  }
+}
+impl Connection for TcpConnection {
   fn send(&mut self,data: &[u8])->Result<()> {
     NET_BUFFER.borrow(|buf| {
       let len: u32=data.len() as u32;
       encode_into(&mut *buf,&len)?;
       buf.extend_from_slice(data);
-      self.write_all(&buf)?;
+      self.stream.write_all(buf)?;
       Ok(())
     })
   }
   fn recv(&mut self,buf: &mut Vec<u8>)->Result<()> {
-    //Get message length from stream
-    let mut len=[0; 4];
-    self.read_exact(&mut len)?;
-    let len: u32=decode_from(&len[..])?;
-    let len=len as usize;
-    //Get a slice of uninitialized data from buffer
-    buf.clear();
-    buf.reserve(len);
-    unsafe{buf.set_len(len)}
-    let slice=&mut buf[..len];
-    //Read into uninitialized slice
-    self.read_exact(slice)?;
+    while !self.take_packet(buf)? {
+      self.fill()?;
+    }
+    Ok(())
+  }
+  fn try_recv(&mut self,buf: &mut Vec<u8>)->Result<bool> {
+    if self.take_packet(buf)? {
+      return Ok(true);
+    }
+    self.stream.set_nonblocking(true)?;
+    let result=loop {
+      match self.fill() {
+        Ok(())=>match self.take_packet(buf) {
+          Ok(false)=>{},
+          result=>break result,
+        },
+        Err(Error::Io(ref err)) if err.kind()==io::ErrorKind::WouldBlock=>break Ok(false),
+        Err(err)=>break Err(err),
+      }
+    };
+    self.stream.set_nonblocking(false)?;
+    result
+  }
+  fn set_timeout(&mut self,timeout: Option<Duration>)->Result<()> {
+    self.stream.set_read_timeout(nonzero_timeout(timeout))?;
     Ok(())
   }
 }
 
-impl Connection for UdpSocket {
+///Packets over UDP, one packet per datagram.
+pub struct UdpConnection {
+  sock: UdpSocket,
+  max_packet_size: usize,
+}
+impl Connection for UdpConnection {
   fn send(&mut self,data: &[u8])->Result<()> {
-    UdpSocket::send(self,data)?;
+    self.sock.send(data)?;
     Ok(())
   }
   fn recv(&mut self,buf: &mut Vec<u8>)->Result<()> {
-    //Get an uninitialized slice as large as possible from the buffer
-    let slice=unsafe{
-      let len=buf.capacity();
-      buf.set_len(len);
-      &mut buf[..]
-    };
-    UdpSocket::recv(self,slice)?;
+    //Leave room for an extra byte to detect oversized datagrams
+    buf.clear();
+    buf.resize(self.max_packet_size+1,0);
+    let len=self.sock.recv(&mut buf[..])?;
+    buf.truncate(len);
+    if len>self.max_packet_size {
+      buf.clear();
+      return Err(DecodeError::TooLarge{len,max: self.max_packet_size}.into());
+    }
     Ok(())
   }
+  fn try_recv(&mut self,buf: &mut Vec<u8>)->Result<bool> {
+    self.sock.set_nonblocking(true)?;
+    let result=self.recv(buf);
+    self.sock.set_nonblocking(false)?;
+    match result {
+      Ok(())=>Ok(true),
+      Err(Error::Io(ref err)) if err.kind()==io::ErrorKind::WouldBlock=>Ok(false),
+      Err(err)=>Err(err),
+    }
+  }
+  fn set_timeout(&mut self,timeout: Option<Duration>)->Result<()> {
+    self.sock.set_read_timeout(nonzero_timeout(timeout))?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::{TcpListener};
+  
+  fn tcp_pair(max_packet_size: usize)->(TcpConnection,TcpStream) {
+    let listener=TcpListener::bind("127.0.0.1:0").unwrap();
+    let local=TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (remote,_)=listener.accept().unwrap();
+    let mut conn=TcpConnection::new(local,max_packet_size);
+    conn.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    (conn,remote)
+  }
+  
+  fn udp_pair(max_packet_size: usize)->(UdpConnection,UdpSocket) {
+    let local=UdpSocket::bind("127.0.0.1:0").unwrap();
+    let remote=UdpSocket::bind("127.0.0.1:0").unwrap();
+    local.connect(remote.local_addr().unwrap()).unwrap();
+    remote.connect(local.local_addr().unwrap()).unwrap();
+    let mut conn=UdpConnection{sock: local,max_packet_size};
+    conn.set_timeout(Some(Duration::from_secs(5))).unwrap();
+    (conn,remote)
+  }
+  
+  fn framed(packets: &[&[u8]])->Vec<u8> {
+    let mut bytes=Vec::new();
+    for packet in packets {
+      encode_into(&mut bytes,&(packet.len() as u32)).unwrap();
+      bytes.extend_from_slice(packet);
+    }
+    bytes
+  }
+  
+  #[test]
+  fn tcp_packet_boundaries() {
+    let (mut conn,mut remote)=tcp_pair(64);
+    let mut buf=Vec::new();
+    //Several packets in a single write
+    let packets: &[&[u8]]=&[b"mmov0123",b"",b"kpal"];
+    remote.write_all(&framed(packets)).unwrap();
+    for &packet in packets {
+      conn.recv(&mut buf).unwrap();
+      assert_eq!(&buf[..],packet);
+    }
+    //A packet split across writes, length prefix included
+    let bytes=framed(&[b"split packet"]);
+    remote.write_all(&bytes[..2]).unwrap();
+    assert!(!conn.try_recv(&mut buf).unwrap());
+    remote.write_all(&bytes[2..7]).unwrap();
+    assert!(!conn.try_recv(&mut buf).unwrap());
+    remote.write_all(&bytes[7..]).unwrap();
+    conn.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..],b"split packet");
+    assert!(!conn.try_recv(&mut buf).unwrap());
+  }
+  
+  #[test]
+  fn tcp_oversized_packet_is_skipped() {
+    let (mut conn,mut remote)=tcp_pair(8);
+    let mut buf=Vec::new();
+    //Larger than a read chunk, so it is discarded across several reads
+    let big=vec![0xaa; TcpConnection::READ_CHUNK*2+10];
+    remote.write_all(&framed(&[b"ping",&big,b"12345678",&big[..9],b"repl"])).unwrap();
+    conn.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..],b"ping");
+    for &len in [big.len(),9].iter() {
+      match conn.recv(&mut buf) {
+        Err(Error::Malformed(DecodeError::TooLarge{len: got,max: 8}))=>assert_eq!(got,len),
+        other=>panic!("expected an oversized packet error, got {:?}",other),
+      }
+      //The stream stays in sync after the oversized packet
+      conn.recv(&mut buf).unwrap();
+      assert_eq!(&buf[..],if len==9 {&b"repl"[..]}else{&b"12345678"[..]});
+    }
+  }
+  
+  #[test]
+  fn udp_datagram_boundaries() {
+    let (mut conn,remote)=udp_pair(8);
+    let mut buf=Vec::new();
+    assert!(!conn.try_recv(&mut buf).unwrap());
+    for datagram in [&b"kpal"[..],b"",b"12345678",b"123456789",b"ping"].iter() {
+      remote.send(datagram).unwrap();
+      match conn.recv(&mut buf) {
+        Ok(())=>assert_eq!(&buf[..],*datagram),
+        Err(Error::Malformed(DecodeError::TooLarge{len: 9,max: 8}))=>assert_eq!(datagram.len(),9),
+        other=>panic!("unexpected result for {:?}: {:?}",datagram,other),
+      }
+    }
+  }
 }
@@ -152,7 +152,7 @@ impl<T: Display> Display for Rect<T> {
   }
 }
 
-#[derive(Serialize,Deserialize,Copy,Clone,Debug,Default,Hash)]
+#[derive(Serialize,Deserialize,Copy,Clone,Debug,Default,Hash,PartialEq)]
 pub struct Pair<T>(pub [T; 2]);
 impl<T> Pair<T> {
   ///Swaps the x and y axes.
@@ -130,11 +130,11 @@ end
 
 --Input management
 local fastest_touch=nil;
-local report_touch,report_key,set_size;
+local report_touch,report_contact,report_key,set_size;
 do
   local last_touch=nil;
-  function report_touch(now,x,y)
-    network.to_server:push{type="touch",x=x,y=y};
+  function report_touch(now,x,y,istouch)
+    network.to_server:push{type="touch",now=now,x=x,y=y,istouch=istouch};
     print("touch on ["..x..", "..y.."]");
     if last_touch then
       local delay=now-last_touch;
@@ -144,13 +144,42 @@ do
     end
     last_touch=now;
   end
+  --LÖVE touch ids can't cross thread boundaries, so map them to integer pointer ids
+  local pointer_ids={};
+  local next_pointer_id=0;
+  function report_contact(now,id,phase,x,y,pressure)
+    local pointer=pointer_ids[id];
+    if not pointer then
+      pointer=next_pointer_id;
+      next_pointer_id=next_pointer_id+1;
+      pointer_ids[id]=pointer;
+    end
+    if phase=="up" then
+      pointer_ids[id]=nil;
+    end
+    network.to_server:push{type="contact",id=pointer,phase=phase,x=x,y=y,pressure=pressure};
+  end
+  local function modifiers()
+    local bits=0;
+    if love.keyboard.isDown("lshift","rshift") then bits=bits+0x1; end
+    if love.keyboard.isDown("lctrl","rctrl") then bits=bits+0x2; end
+    if love.keyboard.isDown("lalt","ralt") then bits=bits+0x4; end
+    if love.keyboard.isDown("lgui","rgui") then bits=bits+0x8; end
+    return bits;
+  end
   function report_key(now,key,scancode,is_down)
-    network.to_server:push{type="key",key=key,scancode=scancode,is_down=is_down};
+    network.to_server:push{type="key",key=key,scancode=scancode,is_down=is_down,modifiers=modifiers()};
     print("key ["..key..", "..scancode.."]");
   end
   function set_size(w,h)
     width,height=w,h;
-    network.to_server:push{type="resize",width=w,height=h};
+    --Report the area covered by system UI as insets from each edge
+    local insets;
+    if love.window.getSafeArea then
+      local x,y,sw,sh=love.window.getSafeArea();
+      insets={x,y,w-x-sw,h-y-sh};
+    end
+    network.to_server:push{type="resize",width=w,height=h,insets=insets};
     print("set size to ["..w..", "..h.."]");
   end
 end
@@ -162,21 +191,31 @@ do
   local mouse_is_down=false;
   local handle={};
   
-  function handle.mousepressed(now,x,y)
+  function handle.mousepressed(now,x,y,button,istouch)
     mouse_is_down=true;
     if track_mouse~="never" then
-      report_touch(now,x,y);
+      report_touch(now,x,y,istouch);
     end
   end
   function handle.mousereleased(now)
     mouse_is_down=false;
   end
-  function handle.mousemoved(now,x,y)
+  function handle.mousemoved(now,x,y,dx,dy,istouch)
     if track_mouse=="always" or (track_mouse=="pressed" and mouse_is_down) then
-      report_touch(now,x,y);
+      report_touch(now,x,y,istouch);
     end
   end
   
+  function handle.touchpressed(now,id,x,y,dx,dy,pressure)
+    report_contact(now,id,"down",x,y,pressure);
+  end
+  function handle.touchmoved(now,id,x,y,dx,dy,pressure)
+    report_contact(now,id,"move",x,y,pressure);
+  end
+  function handle.touchreleased(now,id,x,y,dx,dy,pressure)
+    report_contact(now,id,"up",x,y,pressure);
+  end
+  
   function handle.keypressed(now,key,scancode,is_repeat)
     if is_repeat then return end
     report_key(now,key,scancode,true);
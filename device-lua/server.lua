@@ -6,10 +6,17 @@ local protocols=require "protocols";
 local util=require "util";
 util.redirect_print(net.to_ui);
 require "love.timer";
+require "love.filesystem";
 
-local absm_version={major=1,minor=0};
-local password="";
-local width,height;
+local absm_version={major=1,minor=11};
+--Optional protocol features supported by this server, and the minor version introducing them
+local capabilities={"multitouch","batch","clock","sequence","compact"};
+local capability_since={multitouch=1,pen=2,batch=3,clock=4,sequence=9,compact=11};
+--Minor version that introduced the capabilities header itself
+local capabilities_header_since=5;
+--Rudimentary security: clients must send the contents of `password.txt` in the save directory
+local password=(love.filesystem.read("password.txt") or ""):match("^%s*(.-)%s*$");
+local width,height,insets;
 
 --Initialize protocols
 do
@@ -21,6 +28,9 @@ do
   print("initialized "..#protos.." protocols: "..table.concat(protos,", "));
 end
 
+--Multi-touch contact phases, as sent over the network
+local contact_phases={down=0,move=1,up=2,cancel=3};
+
 --Keep track of remote connections
 local remotes={};
 
@@ -31,13 +41,111 @@ local function kill_remote(remote)
   remotes[remote.remote_id]=nil;
 end
 
---Send a message through a remote connection
-local function send_on_remote(remote,data)
+--Send raw data through a remote connection, killing it on failure
+local function send_raw(remote,data)
   local ok,err=remote.sock.send(data);
   if not ok then
     print("failed to send data to remote "..remote.remote_id);
     kill_remote(remote);
   end
+  remote.last_sent=love.timer.getTime();
+  return ok;
+end
+
+--Wrap an event packet with a sequence number, if the remote asked for them
+local function sequenced(remote,data)
+  if remote.caps.sequence then
+    local seq=remote.seq or 0;
+    remote.seq=(seq+1)%0x100000000;
+    return "seqn"..string.pack(">I4",seq)..data;
+  end
+  return data;
+end
+
+--Send any batched touch samples as a single packet
+local function flush_batch(remote)
+  local batch=remote.batch;
+  remote.batch=nil;
+  if batch then
+    return send_raw(remote,sequenced(remote,"btch"..string.pack(">I2",#batch)..table.concat(batch)));
+  end
+  return true;
+end
+
+--Send a touch sample along with repeats of the previous ones
+local max_redundancy=8;
+local function send_redundant(remote,now,sample)
+  local history=remote.history;
+  history[#history+1]=string.pack(">I8",math.max(0,math.floor((now-remote.epoch)*1e6)))..sample;
+  if #history>remote.redundancy+1 then
+    table.remove(history,1);
+  end
+  local last=remote.sample_seq;
+  remote.sample_seq=(last+1)%0x100000000;
+  remote:send(sequenced(remote,"rdnt"..string.pack(">I4B",last,#history)..table.concat(history)));
+end
+
+--Add a touch sample to the batch of a remote
+local max_batch=32;
+local function batch_sample(remote,now,sample)
+  local batch=remote.batch or {};
+  remote.batch=batch;
+  batch[#batch+1]=string.pack(">I8",math.max(0,math.floor((now-remote.epoch)*1e6)))..sample;
+  if #batch>=max_batch then
+    flush_batch(remote);
+  end
+end
+
+--Send a touch sample in the compact fixed-point format, as a delta from the previous one when
+--it fits, and as a whole sample at least every few samples to make up for lost datagrams
+local compact_refresh=16;
+local function quantize(val,range,steps)
+  return math.floor(math.min(math.max(val/range,0),1)*steps+0.5);
+end
+local function send_compact(remote,x,y,pressure,size)
+  local sample={
+    quantize(x,width,0xffff),quantize(y,height,0xffff),
+    quantize(pressure,1,0xff),quantize(size,1,0xff),
+  };
+  local num=remote.compact_num;
+  remote.compact_num=(num+1)%0x100;
+  local prev=remote.compact_prev;
+  remote.compact_prev=sample;
+  if prev and remote.compact_since<compact_refresh then
+    local delta={};
+    for i=1,4 do
+      delta[i]=sample[i]-prev[i];
+      if delta[i]<-128 or delta[i]>127 then
+        delta=nil;
+        break;
+      end
+    end
+    if delta then
+      remote.compact_since=remote.compact_since+1;
+      remote:send(sequenced(remote,"dmov"..string.pack(">Bbbbb",num,table.unpack(delta))));
+      return;
+    end
+  end
+  remote.compact_since=1;
+  remote:send(sequenced(remote,"cmov"..string.pack(">BI2I2BB",num,table.unpack(sample))));
+end
+
+--Send a message through a remote connection
+--Batched samples are sent first, to keep messages in order
+local function send_on_remote(remote,data)
+  if flush_batch(remote) then
+    send_raw(remote,data);
+  end
+end
+
+--Tell a remote why the connection is being closed, if it understands, and kill it
+local function close_remote(remote,reason)
+  if remote.minor>=8 then
+    send_raw(remote,"bye "..reason);
+  end
+  if remotes[remote.remote_id] then
+    kill_remote(remote);
+  end
 end
 
 --Create and register a remote connection from an ID and some rebuild data
@@ -45,7 +153,16 @@ local function register_remote(remote_id,sock_data)
   local remote={
     remote_id=remote_id,
     stage="disconnected",
+    minor=0,
+    --Optional features agreed on with the remote
+    caps={},
     timeout_on=love.timer.getTime()+2,
+    --Keepalive interval agreed on with the remote, if any
+    keepalive=false,
+    --Previous samples repeated in every touch packet, if any
+    redundancy=false,
+    last_sent=love.timer.getTime(),
+    last_recv=love.timer.getTime(),
   };
   --Create sender function on remote
   local protocol=assert(protocols[sock_data.protocol],"invalid protocol "..tostring(sock_data.protocol));
@@ -82,6 +199,10 @@ end
 --Place screen resolution info into the header table
 local function place_screen_res(headers)
   headers.screen_res=string.pack(">ff",width,height);
+  if insets then
+    headers.safe_insets=string.pack(">ffff",table.unpack(insets));
+  end
+  return headers;
 end
 
 --Send a server info update through the connection.
@@ -103,7 +224,7 @@ local function abortable(abortable_func,remote,...)
     return err;
   else
     print("aborted "..remote.remote_id..": "..err);
-    return kill_remote(remote);
+    return close_remote(remote,err);
   end
 end
 
@@ -120,10 +241,50 @@ local function parse_handshake_open(remote,data)
       " remote ("..major.."."..minor..") != local ("..absm_version.major.."."..absm_version.minor..")",0);
   end
   --Now that version has been checked, there is not as much strain on compatibility
+  if minor<absm_version.minor then
+    print("warning: "..remote.remote_id.." speaks abs-m "..major.."."..minor..
+      ", older than local "..absm_version.major.."."..absm_version.minor);
+  elseif minor>absm_version.minor then
+    print("warning: "..remote.remote_id.." speaks abs-m "..major.."."..minor..
+      ", newer than local "..absm_version.major.."."..absm_version.minor);
+  end
+  remote.minor=math.min(minor,absm_version.minor);
+  --Remotes predating the capabilities header support everything in their minor version
+  remote.caps={};
+  if remote.minor<capabilities_header_since then
+    for _,name in ipairs(capabilities) do
+      remote.caps[name]=capability_since[name]<=remote.minor or nil;
+    end
+  end
   local reportedPassword="";
   for key,val in parse_headers(data,9) do
     if key=="password" then
       reportedPassword=val;
+    elseif key=="client_name" then
+      print(remote.remote_id.." identifies as '"..val.."'");
+    elseif key=="keepalive" then
+      --Accept the proposed keepalive interval within sensible bounds
+      if #val>=4 then
+        remote.keepalive=math.min(math.max(string.unpack(">f",val),0.2),30);
+      else
+        print("keepalive header too short");
+      end
+    elseif key=="redundancy" then
+      if #val>=1 then
+        remote.redundancy=math.min(string.unpack(">B",val),max_redundancy);
+      else
+        print("redundancy header too short");
+      end
+    elseif key=="capabilities" then
+      --Keep only the capabilities supported on both sides
+      local remote_caps={};
+      for name in val:gmatch("[^,%s]+") do
+        remote_caps[name]=true;
+      end
+      remote.caps={};
+      for _,name in ipairs(capabilities) do
+        remote.caps[name]=remote_caps[name];
+      end
     elseif key=="frame_delay" or key=="update_delay" then
       --Update UI fps or ups
       if #val>=4 then
@@ -141,9 +302,24 @@ local function parse_handshake_open(remote,data)
   end
   --Proceed with connection
   if remote.stage=="disconnected" then
-    local headers={};
+    --Touches are sent as raw LÖVE pixel coordinates
+    local headers={capabilities=table.concat(capabilities,","),coordinate_space="pixels"};
+    if remote.keepalive then
+      headers.keepalive=string.pack(">f",remote.keepalive);
+    end
+    if remote.redundancy then
+      headers.redundancy=string.pack(">B",remote.redundancy);
+    end
     place_screen_res(headers);
     send_server_info(remote,headers);
+    --Batched sample timestamps are measured from here
+    remote.epoch=love.timer.getTime();
+    remote.seq=0;
+    remote.sample_seq=0;
+    remote.history={};
+    remote.compact_num=0;
+    remote.compact_prev=nil;
+    remote.compact_since=0;
     remote.stage="connecting";
     remote.timeout_on=love.timer.getTime()+2;
     ui_update_remote(remote,"stage","connecting");
@@ -155,7 +331,7 @@ end
 local function parse_setup_info(remote,data)
   local function abort(why)
     print("connection to "..remote.remote_id.." aborted: "..why);
-    return kill_remote(remote);
+    return close_remote(remote,why);
   end
   
   --Check packet type
@@ -183,6 +359,11 @@ end
 --Parse a received network message
 local function parse_message(remote,data)
   local pack_ty=data:sub(1,4);
+  --Remotes may leave at any stage
+  if pack_ty=="bye " then
+    print(remote.remote_id.." closed the connection: "..data:sub(5));
+    return kill_remote(remote);
+  end
   if remote.stage=="connected" then
   elseif remote.stage=="disconnected" then
     if pack_ty~="absM" then
@@ -205,8 +386,14 @@ local function parse_message(remote,data)
     --Read setup info
     abortable(parse_setup_info,remote,data);
   elseif pack_ty=="ping" then
-    --Reply a ping
-    remote:send("repl"..data:sub(5));
+    --Reply a ping, timestamped for clock synchronization
+    local reply="repl"..data:sub(5);
+    if remote.caps.clock then
+      reply=reply..string.pack(">I8",math.max(0,math.floor((love.timer.getTime()-remote.epoch)*1e6)));
+    end
+    remote:send(reply);
+  elseif pack_ty=="kpal" then
+    --Receiving anything already keeps the connection alive
   else
     print("unknown message type "..pack_ty.."' from "..remote.remote_id);
   end
@@ -220,29 +407,67 @@ local function tick()
   for remote_id,remote in pairs(remotes) do
     if remote.timeout_on and now>=remote.timeout_on then
       print("connection "..remote.remote_id.." timed out");
-      kill_remote(remote);
+      close_remote(remote,"handshake timed out");
+    elseif remote.stage=="connected" and remote.keepalive then
+      --Give up on remotes after a few silent keepalive intervals, and keep quiet ones alive
+      if now-remote.last_recv>=remote.keepalive*3 then
+        print("no keepalive from "..remote.remote_id..", assuming it is gone");
+        close_remote(remote,"keepalive timed out");
+      elseif now-remote.last_sent>=remote.keepalive then
+        remote:send("kpal");
+      end
     end
   end
 end
 
+local next_tick=0;
 while true do
   local msg=net.to_server:demand(tick_delay);
   if msg==nil then
-    --Do a single network tick
-    tick();
+    --Nothing happened for a while, tick below
   elseif msg.type=="touch" then
     --Send touch data to connected remotes
-    
+    local sample=string.pack(">ffff",msg.x,msg.y,msg.pressure or 1,msg.size or 0);
+    for remote_id,remote in pairs(remotes) do
+      --Remotes supporting multi-touch already get real touches as contacts
+      if remote.stage=="connected" and not (msg.istouch and remote.caps.multitouch) then
+        --Clients that ask for compact touches leave out batches, which keep their timestamps
+        if remote.redundancy then
+          send_redundant(remote,msg.now,sample);
+        elseif remote.caps.batch then
+          batch_sample(remote,msg.now,sample);
+        elseif remote.caps.compact then
+          send_compact(remote,msg.x,msg.y,msg.pressure or 1,msg.size or 0);
+        else
+          remote:send(sequenced(remote,"mmov"..sample));
+        end
+      end
+    end
+  elseif msg.type=="contact" then
+    --Send a single multi-touch contact to connected remotes that support it
+    local data="mtch"..string.pack(">I2I4Bffff",
+      1,msg.id,contact_phases[msg.phase],msg.x,msg.y,msg.pressure or 1,msg.size or 0);
+    for remote_id,remote in pairs(remotes) do
+      if remote.stage=="connected" and remote.caps.multitouch then
+        remote:send(sequenced(remote,data));
+      end
+    end
   elseif msg.type=="key" then
     --Send keypress data to connected remotes
-    
+    local data="keyp"..string.pack(">s4s4BI2",msg.key,msg.scancode,msg.is_down and 1 or 0,msg.modifiers);
+    for remote_id,remote in pairs(remotes) do
+      if remote.stage=="connected" then
+        remote:send(sequenced(remote,data));
+      end
+    end
   elseif msg.type=="recv" then
     --Receive raw data through a remote socket
     local remote=assert(remotes[msg.remote_id],"received message before opening connection!");
+    remote.last_recv=love.timer.getTime();
     parse_message(remote,msg.data);
   elseif msg.type=="resize" then
     --Update screen dimensions and notify connected remotes of the change
-    width,height=msg.width,msg.height;
+    width,height,insets=msg.width,msg.height,msg.insets;
     for remote_id,remote in pairs(remotes) do
       if remote.stage=="connected" then
         send_server_info(remote,place_screen_res({}));
@@ -262,4 +487,16 @@ while true do
   else
     error("invalid thread message to server '"..msg.type.."'");
   end
+  --Do a network tick every now and then, even while busy
+  local now=love.timer.getTime();
+  if now>=next_tick then
+    tick();
+    next_tick=now+tick_delay;
+  end
+  --Send batched samples once there is nothing else queued up
+  if net.to_server:getCount()==0 then
+    for remote_id,remote in pairs(remotes) do
+      flush_batch(remote);
+    end
+  end
 end